@@ -1,17 +1,33 @@
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
+    fmt,
     fs::{self, File},
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{mpsc, LazyLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use clap::Parser;
-use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, SeedableRng};
+use clap::{Parser, ValueEnum};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::{OsRng, StdRng},
+    seq::SliceRandom,
+    thread_rng, Rng, SeedableRng,
+};
 use regex::{Regex, RegexBuilder};
+use serde::{Serialize, Serializer};
 use walkdir::WalkDir;
 
 type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
 
+const ART_RATIO: f64 = 0.5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(Parser, Debug)]
 #[command(
     name = "fortuner",
@@ -22,14 +38,46 @@ type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
 pub struct Cli {
     #[arg(
         value_name = "FILE",
-        help = "Input files or directories",
+        help = "Input files or directories (- for stdin)",
         required = true
     )]
     sources: Vec<String>,
-    #[arg(value_name = "PATTERN", help = "Pattern", short = 'm', long)]
-    pattern: Option<Regex>,
+    #[arg(
+        value_name = "PATTERN",
+        help = "Pattern (repeat to match any of several)",
+        short = 'm',
+        long
+    )]
+    pattern: Vec<String>,
+    #[arg(skip)]
+    regex: Vec<Regex>,
     #[arg(value_name = "SEED", help = "Random seed", short, long)]
     seed: Option<u64>,
+    #[arg(
+        value_name = "STR",
+        help = "Derive the random seed from a string",
+        long
+    )]
+    seed_from: Option<String>,
+    #[arg(
+        value_name = "KEY",
+        help = "Key for hashing --seed-from strings",
+        long,
+        default_value_t = 0
+    )]
+    hash_seed: u64,
+    #[arg(
+        value_name = "N",
+        help = "Use N plus the number of days since the epoch as the seed",
+        long
+    )]
+    seed_base: Option<u64>,
+    #[arg(help = "Use the current hour (YYYYMMDDHH, UTC) as the seed", long)]
+    at_hour: bool,
+    #[arg(help = "Draw the random seed from the operating system", long)]
+    os_random: bool,
+    #[arg(help = "Print the random seed to stderr", long)]
+    print_seed: bool,
     #[arg(
         help = "Case-insensitive pattern matching",
         short,
@@ -37,174 +85,2562 @@ pub struct Cli {
         default_value = "false"
     )]
     insensitive: bool,
+    #[arg(value_name = "FILE", help = "Export all fortunes as JSON", long)]
+    export_json: Option<PathBuf>,
+    #[arg(help = "Report fortunes with identical text", long)]
+    find_duplicates: bool,
+    #[arg(
+        value_name = "EOL",
+        help = "Line ending of the output",
+        long,
+        value_enum,
+        default_value_t = LineEnding::Lf
+    )]
+    line_ending: LineEnding,
+    #[arg(help = "Print the list of files to be searched", short, long)]
+    files: bool,
+    #[arg(help = "Print absolute paths in file listings", long)]
+    absolute: bool,
+    #[arg(help = "List every source containing each match", long)]
+    all_sources: bool,
+    #[arg(help = "Fail when there are no fortunes to pick from", long)]
+    fail_on_empty: bool,
+    #[arg(value_name = "N", help = "Read only N randomly chosen files", long)]
+    sample_sources: Option<usize>,
+    #[arg(help = "Print only the source of the picked fortune", long)]
+    print_source_only: bool,
+    #[arg(
+        value_name = "NAME",
+        help = "Treat all fortunes as from one source",
+        long
+    )]
+    merge_as: Option<String>,
+    #[arg(help = "Report the number of bytes written to stdout", long)]
+    report_bytes: bool,
+    #[arg(help = "Skip files that look binary", long)]
+    skip_binary: bool,
+    #[arg(value_name = "N", help = "Print N distinct fortunes", long)]
+    count_out: Option<usize>,
+    #[arg(
+        value_name = "SOURCE=WEIGHT",
+        help = "Relative weight of fortunes from SOURCE",
+        long,
+        value_parser = parse_weight
+    )]
+    weight: Vec<(String, f64)>,
+    #[arg(
+        help = "Print fortunes that do not match the pattern",
+        short = 'v',
+        long
+    )]
+    invert_match: bool,
+    #[arg(help = "Honor \"@source: NAME\" on the first line of a fortune", long)]
+    source_from_first_line: bool,
+    #[arg(
+        value_name = "TEMPLATE",
+        help = "Format fortunes using {source}, {text}, {length}, {id} and {tag}",
+        long
+    )]
+    template: Option<String>,
+    #[arg(value_name = "FILE", help = "Read the output template from FILE", long)]
+    template_file: Option<PathBuf>,
+    #[arg(help = "Show progress while reading files", long)]
+    progress: bool,
+    #[arg(
+        value_name = "CODE",
+        help = "Exit code when no fortune matches the pattern",
+        long,
+        default_value_t = 0
+    )]
+    no_match_exit_code: i32,
+    #[arg(help = "Exclude sources that are mostly ASCII art", long)]
+    text_only: bool,
+    #[arg(help = "Shuffle the lines of the fortune", long)]
+    scramble_lines: bool,
+    #[arg(value_name = "N", help = "Draw only from the Nth source file", long)]
+    source_index: Option<usize>,
+    #[arg(value_name = "N", help = "Leave out the Nth source file", long)]
+    exclude_source_index: Option<usize>,
+    #[arg(help = "Also match the pattern against source names", long)]
+    match_filename: bool,
+    #[arg(
+        value_name = "N",
+        help = "Exclude fortunes with a line shorter than N characters",
+        long
+    )]
+    min_line_length: Option<usize>,
+    #[arg(
+        value_name = "F",
+        help = "Exclude fortunes with less than F (0.0-1.0) alphabetic characters",
+        long,
+        value_parser = parse_ratio
+    )]
+    min_alpha_ratio: Option<f64>,
+    #[arg(
+        value_name = "DIR",
+        help = "Directory for cached fortune indexes",
+        long
+    )]
+    cache_dir: Option<PathBuf>,
+    #[arg(
+        value_name = "HEX",
+        help = "Print the fortune with the given id",
+        long,
+        value_parser = parse_id
+    )]
+    by_id: Option<u64>,
+    #[arg(
+        value_name = "FILE",
+        help = "Append the id, source and seed of each picked fortune to FILE",
+        long
+    )]
+    log: Option<PathBuf>,
+    #[arg(
+        value_name = "FILE",
+        help = "Print fortunes in order, keeping the last position in FILE",
+        long
+    )]
+    round_robin_state: Option<PathBuf>,
+    #[arg(
+        value_name = "FILE",
+        help = "Do not repeat fortunes recorded in FILE until all have been shown",
+        long
+    )]
+    shown_file: Option<PathBuf>,
+    #[arg(help = "Print the probability of the selected fortune to stderr", long)]
+    show_probability: bool,
+    #[arg(
+        value_name = "FILE",
+        help = "Print the fortune last recorded in a --log FILE",
+        long
+    )]
+    replay: Option<PathBuf>,
+    #[arg(
+        value_name = "FILE",
+        help = "Resolve sources through NAME = PATH lines in FILE",
+        long
+    )]
+    alias_file: Option<PathBuf>,
+    #[arg(help = "Print statistics about the fortunes", long)]
+    stats: bool,
+    #[arg(help = "Print picked fortunes, matches or --stats as JSON", long)]
+    json: bool,
+    #[arg(
+        help = "Include the id and length of each fortune in JSON output",
+        long
+    )]
+    json_details: bool,
+    #[arg(help = "Indent JSON output", long)]
+    pretty: bool,
+    #[arg(
+        value_name = "N",
+        help = "Warn in --stats when there are more than N sources",
+        long
+    )]
+    warn_source_count: Option<usize>,
+    #[arg(value_name = "N", help = "Fail on fortunes larger than N bytes", long)]
+    max_fortune_bytes: Option<usize>,
+    #[arg(
+        value_name = "N",
+        help = "Skip files with a line longer than N bytes",
+        long
+    )]
+    max_line_bytes: Option<usize>,
+    #[arg(help = "Remove common leading whitespace from fortunes", long)]
+    dedent: bool,
+    #[arg(help = "Fail on the first error while walking directories", long)]
+    fail_fast: bool,
+    #[arg(
+        value_name = "MIN-MAX",
+        help = "Print the number of fortunes MIN to MAX characters long",
+        long,
+        value_parser = parse_range
+    )]
+    count_range: Option<RangeInclusive<usize>>,
+    #[arg(help = "Print a one-line summary of files and fortunes", long)]
+    summary: bool,
+    #[arg(help = "Drop fortunes whose text was already seen", long)]
+    unique: bool,
+    #[arg(help = "Ignore case and spacing when comparing for --unique", long)]
+    unique_normalize: bool,
+    #[arg(
+        value_name = "SOURCES",
+        help = "Comma-separated sources whose copy --unique keeps first",
+        long,
+        value_delimiter = ','
+    )]
+    priority: Vec<String>,
+    #[arg(
+        value_name = "TEXT",
+        help = "Text to print when no fortune is found",
+        long
+    )]
+    fallback: Option<String>,
+    #[arg(
+        value_name = "DURATION",
+        help = "Only read files modified within DURATION (e.g. 24h, 7d)",
+        long,
+        value_parser = parse_duration
+    )]
+    max_age: Option<Duration>,
+    #[arg(
+        value_name = "DELIM",
+        help = "Print all fortunes separated by DELIM lines",
+        long
+    )]
+    dump_delimited: Option<String>,
+    #[arg(
+        help = "Skip files ignored by .gitignore (requires the gitignore feature)",
+        long
+    )]
+    respect_gitignore: bool,
+    #[arg(help = "Print the position of the fortune in the pool", long)]
+    with_count: bool,
+    #[arg(help = "Treat the pattern as a literal string", long)]
+    literal: bool,
+    #[arg(
+        value_name = "ENC",
+        help = "Character encoding of the input files",
+        long,
+        value_enum,
+        default_value_t = Encoding::Utf8
+    )]
+    encoding: Encoding,
+    #[arg(
+        value_name = "SOURCE=ENC",
+        help = "Character encoding of SOURCE, overriding --encoding",
+        long,
+        value_parser = parse_file_encoding
+    )]
+    file_encoding: Vec<(String, Encoding)>,
+    #[arg(help = "Print the number of sources with a matching fortune", long)]
+    count_matching_sources: bool,
+    #[arg(help = "Print only the first sentence of the fortune", long)]
+    first_sentence: bool,
+    #[arg(help = "Replace curly quotes with straight ones", long)]
+    straight_quotes: bool,
+    #[arg(
+        value_name = "PREFIX",
+        help = "Read only files whose path under a source starts with PREFIX",
+        long
+    )]
+    under: Option<PathBuf>,
+    #[arg(
+        value_name = "N",
+        help = "Print all fortunes packed into blocks of about N characters",
+        long
+    )]
+    pack: Option<usize>,
+    #[arg(
+        value_name = "N",
+        help = "Report fortunes with lines wider than N columns",
+        long
+    )]
+    max_width: Option<usize>,
+    #[arg(
+        help = "Derive a separate seed for each fortune printed by --count-out",
+        long
+    )]
+    reseed: bool,
+    #[arg(value_name = "N", help = "Skip files smaller than N bytes", long)]
+    min_file_bytes: Option<u64>,
+    #[arg(
+        value_name = "N",
+        help = "Capacity of the output buffer in bytes",
+        long,
+        default_value_t = 64 * 1024
+    )]
+    buffer_size: usize,
+    #[arg(
+        value_name = "s/PAT/REPL/[g]",
+        help = "Rename sources in headers with a regex substitution",
+        long,
+        value_parser = parse_rename
+    )]
+    rename: Option<Rename>,
+    #[arg(help = "Separate fortunes with a blank line instead of %", long)]
+    blank_separator: bool,
+    #[arg(help = "Omit the separator after the last fortune", long)]
+    no_trailing_separator: bool,
+    #[arg(help = "Print a count of fixups made while parsing", long)]
+    warnings: bool,
+    #[arg(help = "Print the byte offset and length of each fortune", long)]
+    dump_offsets: bool,
+    #[arg(help = "Report the line endings used by each file", long)]
+    detect_eol: bool,
+    #[arg(value_name = "N", help = "Stop reading after N fortunes", long)]
+    max_total: Option<usize>,
+    #[arg(help = "Tag fortunes with the name of their parent directory", long)]
+    tag_from_dir: bool,
+    #[arg(value_name = "TAG", help = "Draw only from fortunes with TAG", long)]
+    tag: Option<String>,
+    #[arg(help = "Print nothing and exit with 1 when no fortune matches", long)]
+    silent_fail: bool,
+    #[arg(
+        help = "Name sources by their path below the directory common to all files",
+        long
+    )]
+    auto_strip_prefix: bool,
+    #[arg(
+        value_name = "MS",
+        help = "Abort if matching the pattern takes longer than MS milliseconds",
+        long
+    )]
+    match_timeout: Option<u64>,
+    #[arg(
+        value_name = "NAME",
+        help = "Source name for fortunes read from stdin (-)",
+        long,
+        default_value = "stdin"
+    )]
+    stdin_name: String,
+    #[arg(help = "Name fortunes found in a directory after the directory", long)]
+    flatten: bool,
+    #[arg(
+        value_name = "SECS",
+        help = "Skip files that cannot be read within SECS seconds",
+        long
+    )]
+    read_timeout: Option<u64>,
+    #[arg(
+        value_name = "N",
+        help = "Retry failed file reads up to N times",
+        long,
+        default_value_t = 0
+    )]
+    max_retries: usize,
+    #[arg(
+        help = "Weight sources so each contributes equally regardless of size",
+        long
+    )]
+    balance: bool,
+    #[arg(help = "Print the patterns each match matched to stderr", long)]
+    show_pattern: bool,
 }
 
-pub fn get_cli() -> MyResult<Cli> {
-    let mut cli = Cli::parse();
-
-    if let Some(patten) = cli.pattern {
-        cli.pattern = RegexBuilder::new(&patten.to_string())
-            .case_insensitive(cli.insensitive)
-            .build()
-            .map(Some)
-            .map_err(|e| e.to_string())?;
-    };
+impl Cli {
+    pub fn validate(&self) -> MyResult<()> {
+        if self.count_out == Some(0) {
+            return Err("--count-out must be greater than 0".into());
+        }
+        if self.max_total == Some(0) {
+            return Err("--max-total must be greater than 0".into());
+        }
+        if self.sample_sources == Some(0) {
+            return Err("--sample-sources must be greater than 0".into());
+        }
+        if self.match_timeout == Some(0) {
+            return Err("--match-timeout must be greater than 0".into());
+        }
 
-    Ok(cli)
-}
+        if self.pattern.is_empty() {
+            if self.all_sources {
+                return Err("--all-sources requires --pattern".into());
+            }
+            if self.invert_match {
+                return Err("--invert-match requires --pattern".into());
+            }
+            if self.count_matching_sources {
+                return Err("--count-matching-sources requires --pattern".into());
+            }
+            if self.silent_fail {
+                return Err("--silent-fail requires --pattern".into());
+            }
+            if self.match_timeout.is_some() {
+                return Err("--match-timeout requires --pattern".into());
+            }
+            if self.show_pattern {
+                return Err("--show-pattern requires --pattern".into());
+            }
+            if self.rename.is_some() {
+                return Err("--rename requires --pattern".into());
+            }
+        } else if self.count_out.is_some() {
+            return Err("--count-out cannot be used with --pattern".into());
+        } else if self.print_source_only {
+            return Err("--print-source-only cannot be used with --pattern".into());
+        }
 
-fn find_files(paths: &[String]) -> MyResult<Vec<PathBuf>> {
-    let mut files = vec![];
+        if self.print_source_only && self.count_out.is_some() {
+            return Err("--print-source-only cannot be used with --count-out".into());
+        }
+        if self.show_pattern && self.invert_match {
+            return Err("--show-pattern cannot be used with --invert-match".into());
+        }
 
-    for path in paths {
-        match fs::metadata(path) {
-            Err(e) => Err(format!("{}: {}", path, e))?,
-            Ok(_) => {
-                WalkDir::new(path)
-                    .into_iter()
-                    .filter_map(Result::ok)
-                    .filter(|e| {
-                        e.file_type().is_file() && e.path().extension() != Some(OsStr::new("dat"))
-                    })
-                    .for_each(|e| files.push(e.path().to_path_buf()));
+        let seeded = self.seed.is_some() || self.seed_from.is_some();
+        let combinations = [
+            (
+                self.seed_from.is_some() && self.seed.is_some(),
+                "--seed-from cannot be used with --seed",
+            ),
+            (
+                self.hash_seed != 0 && self.seed_from.is_none(),
+                "--hash-seed requires --seed-from",
+            ),
+            (
+                self.seed_base.is_some() && seeded,
+                "--seed-base cannot be used with --seed or --seed-from",
+            ),
+            (
+                self.at_hour && (seeded || self.seed_base.is_some()),
+                "--at-hour cannot be used with --seed, --seed-from or --seed-base",
+            ),
+            (
+                self.os_random && (seeded || self.seed_base.is_some() || self.at_hour),
+                "--os-random cannot be used with --seed, --seed-from, --seed-base or --at-hour",
+            ),
+            (
+                self.template_file.is_some() && self.template.is_some(),
+                "--template-file cannot be used with --template",
+            ),
+            (
+                self.exclude_source_index.is_some() && self.source_index.is_some(),
+                "--exclude-source-index cannot be used with --source-index",
+            ),
+            (
+                self.round_robin_state.is_some()
+                    && (self.seed.is_some() || !self.weight.is_empty()),
+                "--round-robin-state cannot be used with --seed or --weight",
+            ),
+            (
+                self.shown_file.is_some()
+                    && (!self.weight.is_empty()
+                        || self.balance
+                        || self.round_robin_state.is_some()),
+                "--shown-file cannot be used with --weight, --balance or --round-robin-state",
+            ),
+            (
+                self.balance && self.round_robin_state.is_some(),
+                "--balance cannot be used with --round-robin-state",
+            ),
+            (
+                self.show_probability
+                    && (!self.pattern.is_empty()
+                        || self.count_out.is_some()
+                        || self.round_robin_state.is_some()
+                        || self.shown_file.is_some()),
+                "--show-probability cannot be used with --pattern, --count-out, \
+                 --round-robin-state or --shown-file",
+            ),
+            (
+                self.replay.is_some() && self.by_id.is_some(),
+                "--replay cannot be used with --by-id",
+            ),
+            (
+                self.fallback.is_some() && self.fail_on_empty,
+                "--fallback cannot be used with --fail-on-empty",
+            ),
+            (
+                self.silent_fail && (self.fallback.is_some() || self.no_match_exit_code != 0),
+                "--silent-fail cannot be used with --fallback or --no-match-exit-code",
+            ),
+            (
+                self.json_details && !self.json,
+                "--json-details requires --json",
+            ),
+            (self.pretty && !self.json, "--pretty requires --json"),
+            (
+                self.json_details && self.stats,
+                "--json-details cannot be used with --stats",
+            ),
+            (
+                self.warn_source_count.is_some() && !self.stats,
+                "--warn-source-count requires --stats",
+            ),
+            (
+                self.unique_normalize && !self.unique,
+                "--unique-normalize requires --unique",
+            ),
+            (
+                !self.priority.is_empty() && !self.unique,
+                "--priority requires --unique",
+            ),
+            (
+                self.reseed && self.count_out.is_none(),
+                "--reseed requires --count-out",
+            ),
+            (
+                self.tag.is_some() && !self.tag_from_dir,
+                "--tag requires --tag-from-dir",
+            ),
+            (
+                self.json
+                    && (self.show_pattern
+                        || self.all_sources
+                        || self.fallback.is_some()
+                        || self.rename.is_some()),
+                "--json cannot be used with --show-pattern, --all-sources, --fallback or --rename",
+            ),
+            (
+                self.absolute && !(self.files || self.dump_offsets || self.detect_eol),
+                "--absolute requires --files, --dump-offsets or --detect-eol",
+            ),
+            (
+                self.warnings && self.cache_dir.is_some(),
+                "--warnings cannot be used with --cache-dir",
+            ),
+            (
+                self.respect_gitignore && !cfg!(feature = "gitignore"),
+                "--respect-gitignore requires the gitignore feature",
+            ),
+        ];
+        if let Some((_, message)) = combinations.into_iter().find(|(invalid, _)| *invalid) {
+            return Err(message.into());
+        }
+
+        // run handles the first of these it finds, so at most one may be given
+        let modes: Vec<&str> = self
+            .modes()
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag)
+            .collect();
+        if let [first, second, ..] = modes[..] {
+            return Err(format!("{} cannot be used with {}", first, second).into());
+        }
+        let mode = modes.first().copied();
+        if let Some(mode) = mode.filter(|_| !self.pattern.is_empty()) {
+            if !matches!(mode, "--summary" | "--count-matching-sources") {
+                return Err(format!("{} cannot be used with --pattern", mode).into());
+            }
+        }
+        if let Some(mode) = mode.or((!self.pattern.is_empty()).then_some("--pattern")) {
+            let picking = [
+                (self.with_count, "--with-count"),
+                (self.print_source_only, "--print-source-only"),
+                (self.show_probability, "--show-probability"),
+                (self.round_robin_state.is_some(), "--round-robin-state"),
+                (self.shown_file.is_some(), "--shown-file"),
+                (self.log.is_some() && mode != "--count-out", "--log"),
+            ];
+            if let Some((_, option)) = picking.into_iter().find(|(set, _)| *set) {
+                return Err(format!("{} cannot be used with {}", option, mode).into());
+            }
+        }
+        if let Some(mode) = mode.filter(|_| self.json) {
+            if !matches!(mode, "--stats" | "--by-id" | "--count-out") {
+                return Err(format!("--json cannot be used with {}", mode).into());
             }
         }
+        let plain = [
+            (self.print_source_only, "--print-source-only"),
+            (self.with_count, "--with-count"),
+        ];
+        if let Some((_, option)) = plain.into_iter().find(|(set, _)| *set && self.json) {
+            return Err(format!("--json cannot be used with {}", option).into());
+        }
+
+        Ok(())
     }
 
-    files.sort();
-    files.dedup();
-    Ok(files)
-}
+    fn modes(&self) -> [(bool, &'static str); 14] {
+        [
+            (self.dump_offsets, "--dump-offsets"),
+            (self.detect_eol, "--detect-eol"),
+            (self.files, "--files"),
+            (self.export_json.is_some(), "--export-json"),
+            (self.summary, "--summary"),
+            (self.count_range.is_some(), "--count-range"),
+            (self.count_matching_sources, "--count-matching-sources"),
+            (self.stats, "--stats"),
+            (self.dump_delimited.is_some(), "--dump-delimited"),
+            (self.max_width.is_some(), "--max-width"),
+            (self.pack.is_some(), "--pack"),
+            (self.by_id.is_some(), "--by-id"),
+            (self.find_duplicates, "--find-duplicates"),
+            (self.count_out.is_some(), "--count-out"),
+        ]
+    }
 
-#[derive(Debug)]
-pub struct Fortune {
-    source: String,
-    text: String,
-}
+    // compiles patterns and resolves seeds and files named by the options
+    fn prepare(&mut self) -> MyResult<()> {
+        self.regex = self
+            .pattern
+            .iter()
+            .map(|pattern| {
+                let pattern = if self.literal {
+                    regex::escape(pattern)
+                } else {
+                    pattern.to_string()
+                };
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(self.insensitive)
+                    .build()
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<_, _>>()?;
 
-fn read_fortunes(paths: &[PathBuf]) -> MyResult<Vec<Fortune>> {
-    let mut fortunes: Vec<Fortune> = vec![];
-    let mut buffer = vec![];
+        if let Some(s) = &self.seed_from {
+            self.seed = Some(seed_from_str(s, self.hash_seed));
+        }
 
-    for path in paths {
-        let source = path.file_name().unwrap().to_string_lossy().to_string();
-        let file = File::open(path).map_err(|e| format!("{}: {}", source, e))?;
+        if let Some(base) = self.seed_base {
+            self.seed = Some(daily_seed(base, SystemTime::now()));
+        }
+
+        if self.at_hour {
+            self.seed = Some(hourly_seed(SystemTime::now()));
+        }
+
+        if self.os_random {
+            self.seed = Some(OsRng.gen());
+        } else if self.print_seed && self.seed.is_none() {
+            self.seed = Some(thread_rng().gen());
+        }
 
-        for line in BufReader::new(file).lines().map_while(Result::ok) {
-            if line != "%" {
-                buffer.push(line.to_string());
-                continue;
+        if let Some(path) = &self.alias_file {
+            let err = |e: Box<dyn std::error::Error>| format!("{}: {}", path.display(), e);
+            let aliases = fs::read_to_string(path)
+                .map_err(Into::into)
+                .and_then(|contents| parse_aliases(&contents))
+                .map_err(err)?;
+            // alias targets are relative to the alias file
+            let base = path.parent().unwrap_or(Path::new(""));
+            for source in self.sources.iter_mut() {
+                if let Some((_, target)) = aliases.iter().find(|(alias, _)| alias == source) {
+                    *source = base.join(target).to_string_lossy().to_string();
+                }
             }
+        }
 
-            if !buffer.is_empty() {
-                fortunes.push(Fortune {
-                    source: source.clone(),
-                    text: buffer.join("\n"),
-                });
-                buffer.clear();
+        if let Some(path) = &self.replay {
+            self.by_id =
+                Some(last_logged_id(path).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+
+        if let Some(path) = &self.template_file {
+            let template =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.template = Some(template.strip_suffix('\n').unwrap_or(&template).to_string());
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, fortune: &Fortune) -> bool {
+        if self.regex.is_empty() {
+            return true;
+        }
+
+        let is_match = !self.matched_patterns(fortune).is_empty();
+        is_match != self.invert_match
+    }
+
+    fn matched_patterns(&self, fortune: &Fortune) -> Vec<&str> {
+        self.pattern
+            .iter()
+            .zip(&self.regex)
+            .filter(|(_, regex)| {
+                regex.is_match(&fortune.text)
+                    || (self.match_filename && regex.is_match(&fortune.source))
+            })
+            .map(|(pattern, _)| pattern.as_str())
+            .collect()
+    }
+
+    fn check_match_timeout(&self, started: Instant) -> MyResult<()> {
+        match self.match_timeout {
+            Some(ms) if started.elapsed() >= Duration::from_millis(ms) => {
+                Err(format!("Pattern matching exceeded {} ms", ms).into())
             }
+            _ => Ok(()),
         }
     }
 
-    Ok(fortunes)
+    fn matching_sources<'a>(&self, fortunes: &'a [Fortune]) -> Vec<&'a str> {
+        let mut sources: Vec<&str> = fortunes
+            .iter()
+            .filter(|fortune| self.matches(fortune))
+            .map(|fortune| fortune.source.as_str())
+            .collect();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
 }
 
-fn pick_fortune(fortunes: &[Fortune], seed: Option<u64>) -> Option<String> {
-    let fortune = match seed {
-        Some(seed) => fortunes.choose(&mut StdRng::seed_from_u64(seed)),
-        None => fortunes.choose(&mut thread_rng()),
-    }?;
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration \"{}\"", s)),
+    };
 
-    Some(fortune.text.clone())
+    s[..s.len() - 1]
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration \"{}\"", s))
 }
 
-pub fn run(cli: Cli) -> MyResult<()> {
-    let files = find_files(&cli.sources)?;
-    let fortunes = read_fortunes(&files)?;
+fn parse_id(s: &str) -> Result<u64, String> {
+    u64::from_str_radix(s, 16).map_err(|_| format!("invalid id \"{}\"", s))
+}
 
-    if let Some(pattern) = cli.pattern {
-        let mut prev_source = None;
+#[derive(Clone, Debug)]
+pub struct Rename {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+}
 
-        for fortune in fortunes {
-            if pattern.is_match(&fortune.text) {
-                if prev_source.as_ref().map_or(true, |s| s != &fortune.source) {
-                    eprintln!("({})\n%", fortune.source);
-                    prev_source = Some(fortune.source.clone());
-                }
+impl Rename {
+    fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let limit = if self.global { 0 } else { 1 };
+        self.regex.replacen(name, limit, self.replacement.as_str())
+    }
+}
 
-                println!("{}\n%", fortune.text);
-            }
-        }
-    } else {
-        let fortune = pick_fortune(&fortunes, cli.seed);
-        println!(
-            "{}",
-            fortune.unwrap_or_else(|| "No fortunes found".to_string())
-        );
+fn parse_rename(s: &str) -> Result<Rename, String> {
+    let invalid = || format!("expected s/PAT/REPL/[g], got \"{}\"", s);
+    let rest = s.strip_prefix('s').ok_or_else(invalid)?;
+    let delimiter = rest.chars().next().ok_or_else(invalid)?;
+    let parts: Vec<&str> = rest[delimiter.len_utf8()..].split(delimiter).collect();
+
+    match parts[..] {
+        [pattern, replacement, flags] if flags.is_empty() || flags == "g" => Ok(Rename {
+            regex: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+            global: flags == "g",
+        }),
+        _ => Err(invalid()),
     }
+}
 
-    Ok(())
+fn parse_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let invalid = || format!("expected MIN-MAX, got \"{}\"", s);
+    let (min, max) = s.split_once('-').ok_or_else(invalid)?;
+    match (min.parse(), max.parse()) {
+        (Ok(min), Ok(max)) if min <= max => Ok(min..=max),
+        _ => Err(invalid()),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::PathBuf;
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        _ => Err(format!(
+            "expected a ratio between 0.0 and 1.0, got \"{}\"",
+            s
+        )),
+    }
+}
 
-    use super::{find_files, pick_fortune, read_fortunes, Fortune};
+fn parse_weight(s: &str) -> Result<(String, f64), String> {
+    let (source, weight) = s
+        .split_once('=')
+        .ok_or(format!("expected SOURCE=WEIGHT, got \"{}\"", s))?;
+    match weight.parse::<f64>() {
+        Ok(w) if w >= 0.0 && w.is_finite() => Ok((source.to_string(), w)),
+        _ => Err(format!("invalid weight \"{}\"", weight)),
+    }
+}
 
-    #[test]
-    fn test_find_files() {
-        let res = find_files(&["./tests/inputs/jokes".to_string()]);
-        assert!(res.is_ok());
+fn parse_file_encoding(s: &str) -> Result<(String, Encoding), String> {
+    let (source, encoding) = s
+        .split_once('=')
+        .ok_or(format!("expected SOURCE=ENC, got \"{}\"", s))?;
+    Encoding::from_str(encoding, true)
+        .map(|e| (source.to_string(), e))
+        .map_err(|_| format!("invalid encoding \"{}\"", encoding))
+}
 
-        let files = res.unwrap();
-        assert_eq!(files.len(), 1);
-        assert_eq!(
-            files.first().unwrap().to_string_lossy(),
-            "./tests/inputs/jokes"
-        );
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    #[value(name = "utf-8", alias = "utf8")]
+    Utf8,
+    #[value(alias = "iso-8859-1")]
+    Latin1,
+}
 
-        // 存在しないファイルは失敗する
-        let res = find_files(&["/path/does/not/exist".to_string()]);
-        assert!(res.is_err());
+impl Encoding {
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).to_string(),
+            Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        }
+    }
+}
 
-        // 拡張子が .dat 以外の入力ファイルをすべて検索する
-        let res = find_files(&["./tests/inputs".to_string()]);
-        assert!(res.is_ok());
-        let files = res.unwrap();
-        assert_eq!(files.len(), 5);
-        let first = files.first().unwrap().display().to_string();
-        assert!(first.contains("ascii-art"));
-        let last = files.last().unwrap().display().to_string();
-        assert!(last.contains("quotes"));
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
 
-        // 複数のソースに対するテスト
-        // パスは重複無しでソートされた状態である
-        let res = find_files(&[
-            "./tests/inputs/jokes".to_string(),
-            "./tests/inputs/ascii-art".to_string(),
-            "./tests/inputs/jokes".to_string(),
-        ]);
-        assert!(res.is_ok());
-        let files = res.unwrap();
-        assert_eq!(files.len(), 2);
-        if let Some(filename) = files.first().unwrap().file_name() {
-            assert_eq!(filename.to_string_lossy(), "ascii-art".to_string());
+struct LineEndingWriter<W> {
+    inner: W,
+    line_ending: LineEnding,
+}
+
+impl<W: Write> Write for LineEndingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.line_ending == LineEnding::Lf {
+            return self.inner.write(buf);
         }
-        if let Some(filename) = files.last().unwrap().file_name() {
-            assert_eq!(filename.to_string_lossy(), "jokes".to_string());
+
+        for chunk in buf.split_inclusive(|&b| b == b'\n') {
+            match chunk.strip_suffix(b"\n") {
+                Some(line) if !line.ends_with(b"\r") => {
+                    self.inner.write_all(line)?;
+                    self.inner.write_all(b"\r\n")?;
+                }
+                _ => self.inner.write_all(chunk)?,
+            }
         }
+        Ok(buf.len())
     }
 
-    #[test]
-    fn test_read_fortunes() {
-        // 入力ファイルが1つだけの場合
-        let res = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]);
-        assert!(res.is_ok());
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-        if let Ok(fortunes) = res {
-            assert_eq!(fortunes.len(), 6);
-            assert_eq!(
+struct CountingWriter<W> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn output_writer<W: Write>(inner: W, cli: &Cli) -> LineEndingWriter<CountingWriter<BufWriter<W>>> {
+    LineEndingWriter {
+        inner: CountingWriter {
+            inner: BufWriter::with_capacity(cli.buffer_size, inner),
+            count: 0,
+        },
+        line_ending: cli.line_ending,
+    }
+}
+
+pub fn get_cli() -> MyResult<Cli> {
+    let cli = Cli::parse();
+    cli.validate()?;
+    Ok(cli)
+}
+
+fn log_selection(path: &Path, fortune: &Fortune, seed: Option<u64>) -> io::Result<()> {
+    let seed = seed.map_or("-".to_string(), |seed| seed.to_string());
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{:016x}\t{}\t{}", fortune.id(), fortune.source, seed)
+}
+
+fn last_logged_id(path: &Path) -> MyResult<u64> {
+    let log = fs::read_to_string(path)?;
+    let line = log
+        .lines()
+        .rev()
+        .find(|line| !line.is_empty())
+        .ok_or("no logged fortunes")?;
+    let id = line.split('\t').next().unwrap_or_default();
+    parse_id(id).map_err(Into::into)
+}
+
+fn parse_aliases(contents: &str) -> MyResult<Vec<(String, String)>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| match line.split_once('=') {
+            Some((alias, target)) if !alias.trim().is_empty() && !target.trim().is_empty() => {
+                Ok((alias.trim().to_string(), target.trim().to_string()))
+            }
+            _ => Err(format!("line {}: expected NAME = PATH", i + 1).into()),
+        })
+        .collect()
+}
+
+fn seed_from_str(s: &str, key: u64) -> u64 {
+    // FNV-1a, keyed by feeding the key before the string
+    key.to_le_bytes()
+        .iter()
+        .chain(s.as_bytes())
+        .fold(0xcbf29ce484222325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn epoch_secs(now: SystemTime) -> u64 {
+    now.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn daily_seed(base: u64, now: SystemTime) -> u64 {
+    base.wrapping_add(epoch_secs(now) / (24 * 60 * 60))
+}
+
+fn hourly_seed(now: SystemTime) -> u64 {
+    let secs = epoch_secs(now);
+    let hour = secs % (24 * 60 * 60) / (60 * 60);
+
+    // days since the epoch to a (year, month, day) civil date
+    let z = secs / (24 * 60 * 60) + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + u64::from(month <= 2);
+
+    year * 1_000_000 + month * 10_000 + day * 100 + hour
+}
+
+fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut head = vec![];
+    File::open(path)?.take(1024).read_to_end(&mut head)?;
+    Ok(head.contains(&0))
+}
+
+#[derive(Default)]
+struct FindOptions {
+    skip_binary: bool,
+    fail_fast: bool,
+    max_age: Option<Duration>,
+    #[cfg(feature = "gitignore")]
+    respect_gitignore: bool,
+    min_file_bytes: Option<u64>,
+}
+
+fn is_recent(path: &Path, max_age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .map_or(true, |age| age <= max_age)
+        })
+        .unwrap_or(false)
+}
+
+fn walk_entries<T, E: fmt::Display>(
+    entries: impl IntoIterator<Item = Result<T, E>>,
+    fail_fast: bool,
+) -> MyResult<Vec<T>> {
+    let mut found = vec![];
+
+    for entry in entries {
+        match entry {
+            Ok(entry) => found.push(entry),
+            Err(e) if fail_fast => return Err(e.to_string().into()),
+            Err(_) => {}
+        }
+    }
+
+    Ok(found)
+}
+
+fn walk_files(path: &str, opts: &FindOptions) -> MyResult<Vec<PathBuf>> {
+    #[cfg(feature = "gitignore")]
+    if opts.respect_gitignore {
+        // git's own rules: parent .gitignore files and .git/info/exclude apply too
+        let walk = ignore::WalkBuilder::new(path)
+            .standard_filters(false)
+            .git_ignore(true)
+            .git_exclude(true)
+            .parents(true)
+            .require_git(false)
+            .filter_entry(|e| e.file_name() != ".git")
+            .build();
+        return Ok(walk_entries(walk, opts.fail_fast)?
+            .into_iter()
+            .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+            .filter(|e| e.file_name() != ".gitignore")
+            .map(ignore::DirEntry::into_path)
+            .collect());
+    }
+
+    Ok(walk_entries(WalkDir::new(path), opts.fail_fast)?
+        .into_iter()
+        .filter(|e| e.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+        .collect())
+}
+
+fn find_files(paths: &[String], opts: &FindOptions) -> MyResult<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for path in paths {
+        if path == "-" {
+            files.push(PathBuf::from(path));
+            continue;
+        }
+
+        match fs::metadata(path) {
+            Err(e) => Err(format!("{}: {}", path, e))?,
+            Ok(_) => {
+                walk_files(path, opts)?
+                    .into_iter()
+                    .filter(|path| path.extension() != Some(OsStr::new("dat")))
+                    .filter(|path| !opts.skip_binary || !is_binary(path).unwrap_or(false))
+                    .filter(|path| opts.max_age.is_none_or(|age| is_recent(path, age)))
+                    .filter(|path| {
+                        opts.min_file_bytes.is_none_or(|min| {
+                            fs::metadata(path).is_ok_and(|meta| meta.len() >= min)
+                        })
+                    })
+                    .for_each(|path| files.push(path));
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn common_dir(paths: &[PathBuf]) -> Option<PathBuf> {
+    paths
+        .iter()
+        .filter(|path| *path != Path::new("-"))
+        .filter_map(|path| path.parent())
+        .map(Path::to_path_buf)
+        .reduce(|common, dir| {
+            common
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        })
+}
+
+fn dir_tag(path: &Path) -> Option<String> {
+    path.parent()?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+fn rng_from_seed(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+fn sample_sources(files: &[PathBuf], n: usize, seed: Option<u64>) -> Vec<PathBuf> {
+    let mut sampled: Vec<PathBuf> = files
+        .choose_multiple(&mut rng_from_seed(seed), n)
+        .cloned()
+        .collect();
+    sampled.sort();
+    sampled
+}
+
+#[derive(Debug, Serialize)]
+pub struct Fortune {
+    source: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+impl Fortune {
+    fn id(&self) -> u64 {
+        seed_from_str(&self.text, 0)
+    }
+
+    fn length(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    fn details(&self) -> FortuneDetails<'_> {
+        FortuneDetails {
+            fortune: self,
+            id: format!("{:016x}", self.id()),
+            length: self.length(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FortuneDetails<'a> {
+    #[serde(flatten)]
+    fortune: &'a Fortune,
+    id: String,
+    length: usize,
+}
+
+fn render_json(value: &impl Serialize, pretty: bool) -> serde_json::Result<String> {
+    if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+fn fortune_to_json(fortune: Option<&Fortune>, cli: &Cli) -> serde_json::Result<String> {
+    if cli.json_details {
+        render_json(&fortune.map(Fortune::details), cli.pretty)
+    } else {
+        render_json(&fortune, cli.pretty)
+    }
+}
+
+fn fortunes_to_json(fortunes: &[&Fortune], cli: &Cli) -> serde_json::Result<String> {
+    if cli.json_details {
+        let detailed: Vec<_> = fortunes.iter().map(|fortune| fortune.details()).collect();
+        render_json(&detailed, cli.pretty)
+    } else {
+        render_json(&fortunes, cli.pretty)
+    }
+}
+
+fn export_json(fortunes: &[Fortune], path: &Path) -> MyResult<()> {
+    fs::write(path, format!("{}\n", render_json(&fortunes, false)?))
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+#[derive(Default)]
+struct ReadOptions<'a> {
+    progress: Option<&'a mut dyn Write>,
+    cache_dir: Option<&'a Path>,
+    max_fortune_bytes: Option<usize>,
+    max_line_bytes: Option<usize>,
+    encoding: Encoding,
+    file_encodings: &'a [(String, Encoding)],
+    max_total: Option<usize>,
+    strip_prefix: Option<&'a Path>,
+    read_timeout: Option<Duration>,
+    warnings: Option<&'a mut ParseWarnings>,
+    counts: Option<&'a mut Vec<usize>>,
+    stdin_name: Option<&'a str>,
+    flatten: &'a [(PathBuf, String)],
+    max_retries: usize,
+    tag_from_dir: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ParseWarnings {
+    empty_records: usize,
+    unterminated_records: usize,
+    crlf_lines: usize,
+}
+
+impl fmt::Display for ParseWarnings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} parse warnings ({} empty records skipped, {} unterminated records flushed, \
+             {} CRLF line endings fixed)",
+            self.empty_records + self.unterminated_records + self.crlf_lines,
+            self.empty_records,
+            self.unterminated_records,
+            self.crlf_lines
+        )
+    }
+}
+
+#[cfg(test)]
+fn read_fortunes(paths: &[PathBuf]) -> MyResult<Vec<Fortune>> {
+    read_fortunes_with(paths, ReadOptions::default())
+}
+
+fn read_fortunes_with(paths: &[PathBuf], mut opts: ReadOptions) -> MyResult<Vec<Fortune>> {
+    let mut fortunes: Vec<Fortune> = vec![];
+    let mut buffer = vec![];
+    let mut buffer_bytes = 0;
+    let too_large = |source: &str, n: usize| format!("{}: fortune exceeds {} bytes", source, n);
+    let max_total = opts.max_total.unwrap_or(usize::MAX);
+    let mut truncated = false;
+    let mut counts = vec![0; paths.len()];
+    let has_long_line = |source: &str, text: &str| match opts.max_line_bytes {
+        Some(n) if text.lines().any(|line| line.len() > n) => {
+            eprintln!(
+                "Warning: {}: line longer than {} bytes, skipping",
+                source, n
+            );
+            true
+        }
+        _ => false,
+    };
+
+    'files: for (i, path) in paths.iter().enumerate() {
+        let is_stdin = path == Path::new("-");
+        let flattened = opts.flatten.iter().find(|(dir, _)| path.starts_with(dir));
+        let source = if is_stdin {
+            opts.stdin_name.unwrap_or("stdin").to_string()
+        } else if let Some((_, name)) = flattened {
+            name.clone()
+        } else if let Some(relative) = opts.strip_prefix.and_then(|p| path.strip_prefix(p).ok()) {
+            relative.to_string_lossy().to_string()
+        } else {
+            path.file_name().unwrap().to_string_lossy().to_string()
+        };
+        let tag = opts.tag_from_dir.then(|| dir_tag(path)).flatten();
+        let encoding = opts
+            .file_encodings
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == source)
+            .map_or(opts.encoding, |&(_, e)| e);
+
+        let open = || open_source(path, opts.read_timeout);
+        // stdin cannot be read a second time
+        let retries = if is_stdin { 0 } else { opts.max_retries };
+
+        if let Some(cache_dir) = opts.cache_dir.filter(|_| !is_stdin) {
+            let mut bytes = vec![];
+            let read = RetryReader::new(open, retries, RETRY_BACKOFF).read_to_end(&mut bytes);
+            let texts = match read {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    eprintln!("Warning: {}: read timed out, skipping", source);
+                    vec![]
+                }
+                Err(e) => return Err(format!("{}: {}", source, e).into()),
+                Ok(_) => {
+                    let err = |e: io::Error| format!("{}: {}", source, e);
+                    let index = cached_index(cache_dir, path, &bytes).map_err(err)?;
+                    if let Some(n) = opts.max_fortune_bytes {
+                        if index.iter().any(|&(_, len)| len > n as u64) {
+                            return Err(too_large(&source, n).into());
+                        }
+                    }
+                    let mut texts = read_indexed(&bytes, &index, encoding).map_err(err)?;
+                    if texts.iter().any(|text| has_long_line(&source, text)) {
+                        texts.clear();
+                    }
+                    texts
+                }
+            };
+            for text in texts {
+                if fortunes.len() == max_total {
+                    truncated = true;
+                    break 'files;
+                }
+                fortunes.push(Fortune {
+                    source: source.clone(),
+                    text,
+                    tag: tag.clone(),
+                });
+                counts[i] += 1;
+            }
+        } else {
+            let mut reader = BufReader::new(RetryReader::new(open, retries, RETRY_BACKOFF));
+            let file_start = fortunes.len();
+            let mut bytes = vec![];
+
+            loop {
+                bytes.clear();
+                // never buffer more of a line than it takes to know it is too long
+                let limit = opts.max_line_bytes.map_or(u64::MAX, |n| n as u64 + 3);
+                let read = (&mut reader).take(limit).read_until(b'\n', &mut bytes);
+                let content = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+                let crlf = content.strip_suffix(b"\r");
+                let content = crlf.unwrap_or(content);
+
+                let skip = match read {
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        Some("read timed out".to_string())
+                    }
+                    Err(e) => return Err(format!("{}: {}", source, e).into()),
+                    Ok(_) => opts
+                        .max_line_bytes
+                        .filter(|&n| content.len() > n)
+                        .map(|n| format!("line longer than {} bytes", n)),
+                };
+                if let Some(reason) = skip {
+                    eprintln!("Warning: {}: {}, skipping", source, reason);
+                    fortunes.truncate(file_start);
+                    counts[i] = 0;
+                    buffer.clear();
+                    buffer_bytes = 0;
+                    break;
+                }
+
+                if let Some(warnings) = opts.warnings.as_deref_mut().filter(|_| crlf.is_some()) {
+                    warnings.crlf_lines += 1;
+                }
+                let line = (!bytes.is_empty()).then(|| encoding.decode(content));
+
+                if let Some(line) = line.as_deref().filter(|&line| line != "%") {
+                    buffer_bytes += line.len() + usize::from(!buffer.is_empty());
+                    if let Some(n) = opts.max_fortune_bytes.filter(|&n| buffer_bytes > n) {
+                        return Err(too_large(&source, n).into());
+                    }
+                    buffer.push(line.to_string());
+                    continue;
+                }
+
+                let unterminated = line.is_none();
+                if unterminated && buffer.iter().all(|line| line.is_empty()) {
+                    buffer.clear();
+                    buffer_bytes = 0;
+                }
+
+                if !buffer.is_empty() {
+                    if fortunes.len() == max_total {
+                        truncated = true;
+                        break 'files;
+                    }
+                    if let Some(warnings) = opts.warnings.as_deref_mut().filter(|_| unterminated) {
+                        warnings.unterminated_records += 1;
+                    }
+                    fortunes.push(Fortune {
+                        source: source.clone(),
+                        text: buffer.join("\n"),
+                        tag: tag.clone(),
+                    });
+                    counts[i] += 1;
+                    buffer.clear();
+                    buffer_bytes = 0;
+                } else if let Some(warnings) =
+                    opts.warnings.as_deref_mut().filter(|_| !unterminated)
+                {
+                    warnings.empty_records += 1;
+                }
+
+                if unterminated {
+                    break;
+                }
+            }
+        }
+
+        if let Some(progress) = opts.progress.as_mut() {
+            write!(progress, "\r{}/{} files", i + 1, paths.len())?;
+        }
+    }
+
+    if let Some(progress) = opts.progress.as_mut() {
+        writeln!(progress)?;
+    }
+    if truncated {
+        eprintln!("Warning: stopped reading after {} fortunes", max_total);
+    }
+    if let Some(read_counts) = opts.counts {
+        *read_counts = counts;
+    }
+
+    Ok(fortunes)
+}
+
+// reopens the source after a failed open or read and picks up where it stopped
+struct RetryReader<F> {
+    open: F,
+    reader: Option<Box<dyn Read>>,
+    pos: u64,
+    retries: usize,
+    attempt: usize,
+    backoff: Duration,
+}
+
+impl<F: FnMut() -> io::Result<Box<dyn Read>>> RetryReader<F> {
+    fn new(open: F, retries: usize, backoff: Duration) -> Self {
+        RetryReader {
+            open,
+            reader: None,
+            pos: 0,
+            retries,
+            attempt: 0,
+            backoff,
+        }
+    }
+
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => {
+                let mut reader = (self.open)()?;
+                let skipped = io::copy(&mut (&mut reader).take(self.pos), &mut io::sink())?;
+                if skipped < self.pos {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.reader.insert(reader)
+            }
+        };
+        let n = reader.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: FnMut() -> io::Result<Box<dyn Read>>> Read for RetryReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.try_read(buf) {
+                Err(e) if e.kind() != io::ErrorKind::TimedOut && self.attempt < self.retries => {
+                    self.attempt += 1;
+                    self.reader = None;
+                    // linear backoff, capped at 20 steps
+                    thread::sleep(self.backoff * self.attempt.min(20) as u32);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn open_source(path: &Path, timeout: Option<Duration>) -> io::Result<Box<dyn Read>> {
+    let reader: Box<dyn Read + Send> = if path == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+
+    Ok(match timeout {
+        Some(timeout) => Box::new(read_with_timeout(reader, timeout)),
+        None => reader,
+    })
+}
+
+struct TimeoutReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    timeout: Duration,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.position() == self.chunk.get_ref().len() as u64 {
+            match self.chunks.recv_timeout(self.timeout) {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk?),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+// reads on a separate thread so that a source stalled for longer than the timeout is given up
+fn read_with_timeout(mut reader: impl Read + Send + 'static, timeout: Duration) -> TimeoutReader {
+    let (tx, rx) = mpsc::sync_channel(4);
+    thread::spawn(move || loop {
+        let mut chunk = vec![0; 64 * 1024];
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                chunk.truncate(n);
+                if tx.send(Ok(chunk)).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                break;
+            }
+        }
+    });
+
+    TimeoutReader {
+        chunks: rx,
+        timeout,
+        chunk: io::Cursor::new(vec![]),
+    }
+}
+
+fn scan_offsets(bytes: &[u8]) -> Vec<(u64, u64)> {
+    let mut offsets = vec![];
+    let (mut start, mut end, mut pos) = (None, 0, 0);
+    let mut blank = true;
+
+    for line in bytes.split_inclusive(|&b| b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+
+        if content == b"%" {
+            if let Some(start) = start.take() {
+                offsets.push((start, end - start));
+            }
+            blank = true;
+        } else {
+            start.get_or_insert(pos);
+            end = pos + content.len() as u64;
+            blank &= content.is_empty();
+        }
+        pos += line.len() as u64;
+    }
+
+    // a trailing record without a closing delimiter still counts
+    if let Some(start) = start.filter(|_| !blank) {
+        offsets.push((start, end - start));
+    }
+
+    offsets
+}
+
+fn read_index(path: &Path) -> Option<Vec<(u64, u64)>> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .map(|line| {
+            let (offset, len) = line.split_once('\t')?;
+            Some((offset.parse().ok()?, len.parse().ok()?))
+        })
+        .collect()
+}
+
+fn cached_index(cache_dir: &Path, path: &Path, bytes: &[u8]) -> io::Result<Vec<(u64, u64)>> {
+    let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let index_path = cache_dir.join(format!(
+        "{:016x}.idx",
+        seed_from_str(&key.to_string_lossy(), 0)
+    ));
+
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified());
+    if let (Ok(index_time), Ok(source_time)) = (modified(&index_path), modified(path)) {
+        if index_time >= source_time {
+            if let Some(index) = read_index(&index_path) {
+                return Ok(index);
+            }
+        }
+    }
+
+    let index = scan_offsets(bytes);
+    let contents: String = index
+        .iter()
+        .map(|(offset, len)| format!("{}\t{}\n", offset, len))
+        .collect();
+    fs::create_dir_all(cache_dir)?;
+    fs::write(&index_path, contents)?;
+
+    Ok(index)
+}
+
+fn read_indexed(bytes: &[u8], index: &[(u64, u64)], encoding: Encoding) -> io::Result<Vec<String>> {
+    index
+        .iter()
+        .map(|&(offset, len)| {
+            let record = bytes
+                .get(offset as usize..(offset + len) as usize)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "index is past the end of file",
+                    )
+                })?;
+            Ok(encoding.decode(record).replace("\r\n", "\n"))
+        })
+        .collect()
+}
+
+fn apply_source_directives(fortunes: Vec<Fortune>) -> Vec<Fortune> {
+    fortunes
+        .into_iter()
+        .filter_map(|fortune| {
+            let (first, rest) = fortune.text.split_once('\n').unwrap_or((&fortune.text, ""));
+            match first.strip_prefix("@source:") {
+                Some(_) if rest.is_empty() => None,
+                Some(source) => Some(Fortune {
+                    source: source.trim().to_string(),
+                    text: rest.to_string(),
+                    tag: fortune.tag.clone(),
+                }),
+                None => Some(fortune),
+            }
+        })
+        .collect()
+}
+
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(source|text|length|id|tag)\}").unwrap());
+
+fn render_template(template: &str, fortune: &Fortune, text: &str) -> String {
+    PLACEHOLDER
+        .replace_all(template, |caps: &regex::Captures| match &caps[1] {
+            "source" => fortune.source.clone(),
+            "text" => text.to_string(),
+            "length" => fortune.length().to_string(),
+            "tag" => fortune.tag.clone().unwrap_or_default(),
+            _ => format!("{:016x}", fortune.id()),
+        })
+        .into_owned()
+}
+
+fn art_ratio<'a>(texts: impl IntoIterator<Item = &'a str>) -> f64 {
+    let (symbols, total) = texts
+        .into_iter()
+        .flat_map(str::chars)
+        .filter(|c| !c.is_whitespace())
+        .fold((0, 0), |(symbols, total), c| {
+            (symbols + usize::from(!c.is_alphanumeric()), total + 1)
+        });
+
+    match total {
+        0 => 0.0,
+        _ => symbols as f64 / total as f64,
+    }
+}
+
+fn alpha_ratio(text: &str) -> f64 {
+    let (alpha, total) = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .fold((0, 0), |(alpha, total), c| {
+            (alpha + usize::from(c.is_alphabetic()), total + 1)
+        });
+
+    match total {
+        0 => 0.0,
+        _ => alpha as f64 / total as f64,
+    }
+}
+
+fn exclude_art_sources(fortunes: Vec<Fortune>) -> Vec<Fortune> {
+    let mut texts: HashMap<&str, Vec<&str>> = HashMap::new();
+    for fortune in &fortunes {
+        texts
+            .entry(&fortune.source)
+            .or_default()
+            .push(&fortune.text);
+    }
+
+    let art: Vec<String> = texts
+        .into_iter()
+        .filter(|(_, texts)| art_ratio(texts.iter().copied()) > ART_RATIO)
+        .map(|(source, _)| source.to_string())
+        .collect();
+
+    fortunes
+        .into_iter()
+        .filter(|fortune| !art.contains(&fortune.source))
+        .collect()
+}
+
+fn scramble_lines(text: &str, rng: &mut impl Rng) -> String {
+    let mut lines: Vec<&str> = text.lines().collect();
+    lines.shuffle(rng);
+    lines.join("\n")
+}
+
+fn has_short_line(text: &str, min: usize) -> bool {
+    text.lines()
+        .map(|line| line.chars().filter(|c| !c.is_whitespace()).count())
+        .any(|len| len > 0 && len < min)
+}
+
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .reduce(|common, indent| {
+            let len = common
+                .chars()
+                .zip(indent.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.len_utf8())
+                .sum();
+            &common[..len]
+        })
+        .unwrap_or("");
+
+    text.lines()
+        .map(|line| line.strip_prefix(indent).unwrap_or(line.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn straight_quotes(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{201c}' | '\u{201d}' => '"',
+            '\u{2018}' | '\u{2019}' => '\'',
+            c => c,
+        })
+        .collect()
+}
+
+fn first_sentence(text: &str) -> &str {
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if matches!(c, '.' | '!' | '?') && chars.peek().is_none_or(|(_, next)| next.is_whitespace())
+        {
+            return &text[..i + 1];
+        }
+    }
+
+    text
+}
+
+fn pick_fortune_full(fortunes: &[Fortune], seed: Option<u64>) -> Option<&Fortune> {
+    match seed {
+        Some(seed) => fortunes.choose(&mut StdRng::seed_from_u64(seed)),
+        None => fortunes.choose(&mut thread_rng()),
+    }
+}
+
+fn fortune_weights(fortunes: &[Fortune], weights: &[(String, f64)]) -> Vec<f64> {
+    fortunes
+        .iter()
+        .map(|fortune| {
+            weights
+                .iter()
+                .rev()
+                .find(|(source, _)| source == &fortune.source)
+                .map_or(1.0, |(_, w)| *w)
+        })
+        .collect()
+}
+
+fn balance_weights(fortunes: &[Fortune], weights: &[(String, f64)]) -> Vec<(String, f64)> {
+    let mut counts: Vec<(String, usize)> = vec![];
+    for fortune in fortunes {
+        match counts
+            .iter_mut()
+            .find(|(source, _)| source == &fortune.source)
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((fortune.source.clone(), 1)),
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|(source, count)| {
+            let weight = weights
+                .iter()
+                .rev()
+                .find(|(s, _)| s == &source)
+                .map_or(1.0, |(_, w)| *w);
+            (source, weight / count as f64)
+        })
+        .collect()
+}
+
+fn read_shown_ids(path: &Path) -> HashSet<u64> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| u64::from_str_radix(line.trim(), 16).ok())
+        .collect()
+}
+
+fn pick_unshown<'a>(
+    fortunes: &'a [Fortune],
+    shown: &HashSet<u64>,
+    rng: &mut impl Rng,
+) -> (Option<&'a Fortune>, bool) {
+    let unshown: Vec<&Fortune> = fortunes
+        .iter()
+        .filter(|fortune| !shown.contains(&fortune.id()))
+        .collect();
+
+    // once every fortune has been shown, start over with the whole pool
+    match unshown.choose(rng) {
+        Some(fortune) => (Some(fortune), false),
+        None => (fortunes.choose(rng), true),
+    }
+}
+
+fn selection_probability(weights: &[f64], index: usize) -> f64 {
+    let total: f64 = weights.iter().sum();
+    match weights.get(index) {
+        Some(weight) if total > 0.0 => weight / total,
+        _ => 0.0,
+    }
+}
+
+fn next_round_robin(last: Option<usize>, len: usize) -> Option<usize> {
+    match last {
+        _ if len == 0 => None,
+        Some(last) => Some((last + 1) % len),
+        None => Some(0),
+    }
+}
+
+fn pick_weighted<'a>(
+    fortunes: &'a [Fortune],
+    weights: &[f64],
+    n: usize,
+    rng: &mut impl Rng,
+) -> Vec<&'a Fortune> {
+    pick_weighted_by(fortunes, weights, n, |dist, _| dist.sample(rng))
+}
+
+fn pick_reseeded<'a>(
+    fortunes: &'a [Fortune],
+    weights: &[f64],
+    n: usize,
+    seed: u64,
+) -> Vec<&'a Fortune> {
+    pick_weighted_by(fortunes, weights, n, |dist, i| {
+        // each output gets its own seed, derived from the base seed and its index
+        let sub_seed = seed_from_str(&i.to_string(), seed);
+        dist.sample(&mut StdRng::seed_from_u64(sub_seed))
+    })
+}
+
+fn pick_weighted_by<'a>(
+    fortunes: &'a [Fortune],
+    weights: &[f64],
+    n: usize,
+    mut sample: impl FnMut(&WeightedIndex<f64>, usize) -> usize,
+) -> Vec<&'a Fortune> {
+    let mut weights = weights.to_vec();
+    let mut picked = vec![];
+
+    while picked.len() < n {
+        let Ok(dist) = WeightedIndex::new(&weights) else {
+            break;
+        };
+        let i = sample(&dist, picked.len());
+        picked.push(&fortunes[i]);
+        weights[i] = 0.0;
+    }
+
+    picked
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    files: usize,
+    fortunes: usize,
+    chars: usize,
+    words: usize,
+    shortest: usize,
+    longest: usize,
+    average: f64,
+    #[serde(serialize_with = "serialize_pairs")]
+    bytes: Vec<(String, usize)>,
+}
+
+// writes (key, value) pairs as a JSON object, keeping their order
+fn serialize_pairs<S: Serializer>(pairs: &[(String, usize)], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_map(pairs.iter().map(|(k, v)| (k, v)))
+}
+
+impl Stats {
+    fn new(files: usize, fortunes: &[Fortune]) -> Self {
+        let lengths: Vec<usize> = fortunes.iter().map(Fortune::length).collect();
+        let chars = lengths.iter().sum();
+        let mut bytes: Vec<(String, usize)> = vec![];
+        for fortune in fortunes {
+            match bytes
+                .iter_mut()
+                .find(|(source, _)| *source == fortune.source)
+            {
+                Some((_, n)) => *n += fortune.text.len(),
+                None => bytes.push((fortune.source.clone(), fortune.text.len())),
+            }
+        }
+
+        Stats {
+            files,
+            fortunes: fortunes.len(),
+            chars,
+            words: fortunes
+                .iter()
+                .map(|f| f.text.split_whitespace().count())
+                .sum(),
+            shortest: lengths.iter().copied().min().unwrap_or(0),
+            longest: lengths.iter().copied().max().unwrap_or(0),
+            average: match fortunes.len() {
+                0 => 0.0,
+                n => chars as f64 / n as f64,
+            },
+            bytes,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "files: {}", self.files)?;
+        writeln!(f, "fortunes: {}", self.fortunes)?;
+        writeln!(f, "chars: {}", self.chars)?;
+        writeln!(f, "words: {}", self.words)?;
+        writeln!(f, "shortest: {}", self.shortest)?;
+        writeln!(f, "longest: {}", self.longest)?;
+        write!(f, "average: {:.2}", self.average)?;
+        for (source, n) in &self.bytes {
+            write!(f, "\nbytes ({}): {}", source, n)?;
+        }
+        Ok(())
+    }
+}
+
+fn dedup_key(text: &str, normalize: bool) -> String {
+    if normalize {
+        text.split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ")
+    } else {
+        text.to_string()
+    }
+}
+
+fn dedup_fortunes(fortunes: Vec<Fortune>, normalize: bool, priority: &[String]) -> Vec<Fortune> {
+    let rank = |source: &str| {
+        priority
+            .iter()
+            .position(|p| p == source)
+            .unwrap_or(priority.len())
+    };
+    let mut kept: HashMap<String, usize> = HashMap::new();
+
+    for (i, fortune) in fortunes.iter().enumerate() {
+        kept.entry(dedup_key(&fortune.text, normalize))
+            .and_modify(|k| {
+                if rank(&fortune.source) < rank(&fortunes[*k].source) {
+                    *k = i;
+                }
+            })
+            .or_insert(i);
+    }
+
+    let kept: HashSet<usize> = kept.into_values().collect();
+    fortunes
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| kept.contains(i))
+        .map(|(_, fortune)| fortune)
+        .collect()
+}
+
+fn dump_delimited(out: &mut impl Write, fortunes: &[Fortune], delimiter: &str) -> io::Result<()> {
+    for fortune in fortunes {
+        writeln!(out, "{}\n{}", fortune.text, delimiter)?;
+    }
+    Ok(())
+}
+
+fn find_wide_fortunes(fortunes: &[Fortune], width: usize) -> Vec<(&str, usize)> {
+    let mut indexes: HashMap<&str, usize> = HashMap::new();
+
+    fortunes
+        .iter()
+        .filter_map(|fortune| {
+            let index = indexes.entry(&fortune.source).or_default();
+            *index += 1;
+            fortune
+                .text
+                .lines()
+                .any(|line| line.chars().count() > width)
+                .then_some((fortune.source.as_str(), *index))
+        })
+        .collect()
+}
+
+fn pack_fortunes(fortunes: &[Fortune], width: usize) -> Vec<String> {
+    let mut blocks: Vec<(String, usize)> = vec![];
+
+    for fortune in fortunes {
+        match blocks.last_mut() {
+            Some((block, len)) if *len + 1 + fortune.length() <= width => {
+                block.push(' ');
+                block.push_str(&fortune.text);
+                *len += 1 + fortune.length();
+            }
+            _ => blocks.push((fortune.text.clone(), fortune.length())),
+        }
+    }
+
+    blocks.into_iter().map(|(block, _)| block).collect()
+}
+
+fn detect_eol(bytes: &[u8]) -> &'static str {
+    let lines = bytes.iter().filter(|&&b| b == b'\n').count();
+    let crlf = bytes.windows(2).filter(|w| w == b"\r\n").count();
+
+    match (lines, crlf) {
+        (0, _) => "none",
+        (_, 0) => "LF",
+        (n, m) if n == m => "CRLF",
+        _ => "mixed",
+    }
+}
+
+fn write_separated(out: &mut impl Write, text: &str, index: usize, cli: &Cli) -> io::Result<()> {
+    // a blank line only ever goes between fortunes, so it is never trailing
+    let trailing = !cli.blank_separator && !cli.no_trailing_separator;
+    if index > 0 && !trailing {
+        writeln!(out, "{}", if cli.blank_separator { "" } else { "%" })?;
+    }
+    writeln!(out, "{}", text)?;
+    if trailing {
+        writeln!(out, "%")?;
+    }
+    Ok(())
+}
+
+fn find_duplicates(fortunes: &[Fortune]) -> Vec<(&str, Vec<&str>)> {
+    let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+    let mut index: HashMap<&str, usize> = HashMap::new();
+
+    for fortune in fortunes {
+        match index.get(fortune.text.as_str()) {
+            Some(&i) => groups[i].1.push(&fortune.source),
+            None => {
+                index.insert(&fortune.text, groups.len());
+                groups.push((&fortune.text, vec![&fortune.source]));
+            }
+        }
+    }
+
+    groups.retain(|(_, sources)| sources.len() > 1);
+    groups
+}
+
+pub fn run(mut cli: Cli) -> MyResult<i32> {
+    cli.prepare()?;
+    if let Some(seed) = cli.seed.filter(|_| cli.print_seed) {
+        eprintln!("Seed: {}", seed);
+    }
+
+    let mut out = output_writer(io::stdout().lock(), &cli);
+
+    let matches = run_to(&cli, &mut out)?;
+    out.flush()?;
+
+    if cli.report_bytes {
+        eprintln!("{} bytes written", out.inner.count);
+    }
+
+    match matches {
+        Some(0) if cli.silent_fail => Ok(1),
+        Some(0) => Ok(cli.no_match_exit_code),
+        _ => Ok(0),
+    }
+}
+
+fn run_to(cli: &Cli, out: &mut impl Write) -> MyResult<Option<usize>> {
+    let mut files = find_files(
+        &cli.sources,
+        &FindOptions {
+            skip_binary: cli.skip_binary,
+            fail_fast: cli.fail_fast,
+            max_age: cli.max_age,
+            #[cfg(feature = "gitignore")]
+            respect_gitignore: cli.respect_gitignore,
+            min_file_bytes: cli.min_file_bytes,
+        },
+    )?;
+    if let Some(prefix) = &cli.under {
+        files.retain(|path| {
+            path.starts_with(prefix)
+                || cli.sources.iter().any(|root| {
+                    path.strip_prefix(root)
+                        .is_ok_and(|relative| relative.starts_with(prefix))
+                })
+        });
+    }
+    if let Some(tag) = &cli.tag {
+        // fortunes are tagged with the same name, so whole files can be left unread
+        files.retain(|path| dir_tag(path).as_deref() == Some(tag.as_str()));
+    }
+    if let Some(n) = cli.sample_sources {
+        files = sample_sources(&files, n, cli.seed);
+    }
+    if let Some(n) = cli.source_index {
+        let file = files.get(n).ok_or(format!(
+            "--source-index {} out of range ({} sources)",
+            n,
+            files.len()
+        ))?;
+        files = vec![file.clone()];
+    }
+    if let Some(n) = cli.exclude_source_index {
+        if n >= files.len() {
+            return Err(format!(
+                "--exclude-source-index {} out of range ({} sources)",
+                n,
+                files.len()
+            )
+            .into());
+        }
+        files.remove(n);
+    }
+    let common = cli.auto_strip_prefix.then(|| common_dir(&files)).flatten();
+    let flatten: Vec<(PathBuf, String)> = cli
+        .sources
+        .iter()
+        .filter(|source| cli.flatten && Path::new(source).is_dir())
+        .filter_map(|source| {
+            let dir = fs::canonicalize(source).ok()?;
+            Some((
+                PathBuf::from(source),
+                dir.file_name()?.to_string_lossy().to_string(),
+            ))
+        })
+        .collect();
+    let listed = |path: &Path| {
+        if cli.absolute {
+            fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+        } else {
+            path.to_path_buf()
+        }
+    };
+
+    // these modes look at the raw bytes, so they run before stdin is consumed
+    let read_bytes = |path: &Path| -> MyResult<Vec<u8>> {
+        let mut bytes = vec![];
+        open_source(path, cli.read_timeout.map(Duration::from_secs))
+            .and_then(|mut reader| reader.read_to_end(&mut bytes))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(bytes)
+    };
+
+    if cli.dump_offsets {
+        for path in &files {
+            let bytes = read_bytes(path)?;
+            for (offset, len) in scan_offsets(&bytes) {
+                writeln!(out, "{}\t{}\t{}", listed(path).display(), offset, len)?;
+            }
+        }
+        return Ok(None);
+    }
+
+    if cli.detect_eol {
+        for path in &files {
+            let bytes = read_bytes(path)?;
+            writeln!(out, "{:>5} {}", detect_eol(&bytes), listed(path).display())?;
+        }
+        return Ok(None);
+    }
+
+    let mut warnings = ParseWarnings::default();
+    let mut counts = vec![];
+    let mut stderr = io::stderr();
+    let mut fortunes = read_fortunes_with(
+        &files,
+        ReadOptions {
+            progress: cli.progress.then_some(&mut stderr as &mut dyn Write),
+            cache_dir: cli.cache_dir.as_deref(),
+            max_fortune_bytes: cli.max_fortune_bytes,
+            max_line_bytes: cli.max_line_bytes,
+            encoding: cli.encoding,
+            file_encodings: &cli.file_encoding,
+            max_total: cli.max_total,
+            strip_prefix: common.as_deref(),
+            read_timeout: cli.read_timeout.map(Duration::from_secs),
+            warnings: cli.warnings.then_some(&mut warnings),
+            counts: Some(&mut counts),
+            stdin_name: Some(&cli.stdin_name),
+            flatten: &flatten,
+            max_retries: cli.max_retries,
+            tag_from_dir: cli.tag_from_dir,
+        },
+    )?;
+    if cli.warnings {
+        eprintln!("{}", warnings);
+    }
+    if cli.source_from_first_line {
+        fortunes = apply_source_directives(fortunes);
+    }
+    if cli.text_only {
+        fortunes = exclude_art_sources(fortunes);
+    }
+    if let Some(min) = cli.min_line_length {
+        fortunes.retain(|fortune| !has_short_line(&fortune.text, min));
+    }
+    if let Some(min) = cli.min_alpha_ratio {
+        fortunes.retain(|fortune| alpha_ratio(&fortune.text) >= min);
+    }
+    if cli.unique {
+        fortunes = dedup_fortunes(fortunes, cli.unique_normalize, &cli.priority);
+    }
+    if let Some(name) = &cli.merge_as {
+        fortunes.iter_mut().for_each(|f| f.source = name.clone());
+    }
+    let source_weights = if cli.balance {
+        balance_weights(&fortunes, &cli.weight)
+    } else {
+        cli.weight.clone()
+    };
+
+    let format = |fortune: &Fortune| {
+        let mut text = fortune.text.clone();
+        if cli.dedent {
+            text = dedent(&text);
+        }
+        if cli.first_sentence {
+            text = first_sentence(&text).to_string();
+        }
+        if cli.straight_quotes {
+            text = straight_quotes(&text);
+        }
+        if cli.scramble_lines {
+            text = scramble_lines(&text, &mut rng_from_seed(cli.seed));
+        }
+
+        match &cli.template {
+            Some(template) => render_template(template, fortune, &text),
+            None => text,
+        }
+    };
+
+    if cli.files {
+        // shares are of the fortunes read, before any filtering
+        let total: usize = counts.iter().sum();
+        for (path, &count) in files.iter().zip(&counts) {
+            let label = match count {
+                0 => "empty".to_string(),
+                n => format!("{:.2}%", n as f64 * 100.0 / total as f64),
+            };
+            writeln!(out, "{:>7} {}", label, listed(path).display())?;
+        }
+        return Ok(None);
+    }
+
+    if let Some(path) = &cli.export_json {
+        return export_json(&fortunes, path).map(|_| None);
+    }
+
+    if cli.summary {
+        writeln!(
+            out,
+            "{} files, {} fortunes, {} sources matched",
+            files.len(),
+            fortunes.len(),
+            cli.matching_sources(&fortunes).len()
+        )?;
+        return Ok(None);
+    }
+
+    if let Some(range) = &cli.count_range {
+        let count = fortunes
+            .iter()
+            .filter(|fortune| range.contains(&fortune.length()))
+            .count();
+        writeln!(out, "{}", count)?;
+        return Ok(None);
+    }
+
+    if cli.count_matching_sources {
+        writeln!(out, "{}", cli.matching_sources(&fortunes).len())?;
+        return Ok(None);
+    }
+
+    if cli.stats {
+        if let Some(n) = cli.warn_source_count.filter(|&n| files.len() > n) {
+            eprintln!(
+                "Warning: {} sources exceed --warn-source-count {}",
+                files.len(),
+                n
+            );
+        }
+        let stats = Stats::new(files.len(), &fortunes);
+        if cli.json {
+            writeln!(out, "{}", render_json(&stats, cli.pretty)?)?;
+        } else {
+            writeln!(out, "{}", stats)?;
+        }
+        return Ok(None);
+    }
+
+    if let Some(delimiter) = &cli.dump_delimited {
+        dump_delimited(out, &fortunes, delimiter)?;
+        return Ok(None);
+    }
+
+    if let Some(width) = cli.max_width {
+        let wide = find_wide_fortunes(&fortunes, width);
+        for (source, index) in &wide {
+            writeln!(
+                out,
+                "{}: fortune {} has a line wider than {}",
+                source, index, width
+            )?;
+        }
+        if !wide.is_empty() {
+            return Err(format!("{} fortunes have lines wider than {}", wide.len(), width).into());
+        }
+        return Ok(None);
+    }
+
+    if let Some(width) = cli.pack {
+        for (i, block) in pack_fortunes(&fortunes, width).iter().enumerate() {
+            write_separated(out, block, i, cli)?;
+        }
+        return Ok(None);
+    }
+
+    if let Some(id) = cli.by_id {
+        let fortune = fortunes
+            .iter()
+            .find(|fortune| fortune.id() == id)
+            .ok_or(format!("No fortune with id {:016x}", id))?;
+        if cli.json {
+            writeln!(out, "{}", fortune_to_json(Some(fortune), cli)?)?;
+        } else {
+            writeln!(out, "{}", format(fortune))?;
+        }
+        return Ok(None);
+    }
+
+    if cli.find_duplicates {
+        for (text, sources) in find_duplicates(&fortunes) {
+            writeln!(out, "({})\n{}\n%", sources.join(", "), text)?;
+        }
+        return Ok(None);
+    }
+
+    if !cli.regex.is_empty() && cli.json {
+        let started = Instant::now();
+        let mut matched = vec![];
+        for fortune in &fortunes {
+            cli.check_match_timeout(started)?;
+            if cli.matches(fortune) {
+                matched.push(fortune);
+            }
+        }
+        let matches = matched.len();
+        if matches > 0 || !cli.silent_fail {
+            writeln!(out, "{}", fortunes_to_json(&matched, cli)?)?;
+        }
+        return Ok(Some(matches));
+    }
+
+    if !cli.regex.is_empty() {
+        let mut prev_source = None;
+        let mut matches = 0;
+        let duplicates: HashMap<&str, Vec<&str>> = if cli.all_sources {
+            find_duplicates(&fortunes).into_iter().collect()
+        } else {
+            HashMap::new()
+        };
+
+        let started = Instant::now();
+
+        for fortune in &fortunes {
+            cli.check_match_timeout(started)?;
+            if cli.matches(fortune) {
+                matches += 1;
+                if prev_source != Some(&fortune.source) {
+                    let source = match &cli.rename {
+                        Some(rename) => rename.apply(&fortune.source),
+                        None => fortune.source.as_str().into(),
+                    };
+                    // headers go to stderr, so let earlier fortunes out first
+                    out.flush()?;
+                    eprintln!("({})\n%", source);
+                    prev_source = Some(&fortune.source);
+                }
+                if cli.show_pattern {
+                    out.flush()?;
+                    eprintln!("[{}]", cli.matched_patterns(fortune).join(", "));
+                }
+
+                let mut text = format(fortune);
+                if cli.all_sources {
+                    let mut sources = duplicates
+                        .get(fortune.text.as_str())
+                        .cloned()
+                        .unwrap_or_else(|| vec![&fortune.source]);
+                    sources.dedup();
+                    text += &format!("\n({})", sources.join(", "));
+                }
+                write_separated(out, &text, matches - 1, cli)?;
+            }
+        }
+
+        if matches == 0 {
+            if let Some(fallback) = &cli.fallback {
+                writeln!(out, "{}", fallback)?;
+                return Ok(None);
+            }
+        }
+        return Ok(Some(matches));
+    } else if let Some(n) = cli.count_out {
+        let weights = fortune_weights(&fortunes, &source_weights);
+        let picked = if cli.reseed {
+            let seed = cli.seed.unwrap_or_else(|| thread_rng().gen());
+            pick_reseeded(&fortunes, &weights, n, seed)
+        } else {
+            pick_weighted(&fortunes, &weights, n, &mut rng_from_seed(cli.seed))
+        };
+        if picked.is_empty() {
+            if cli.fail_on_empty {
+                return Err("No fortunes found".into());
+            }
+            if let Some(fallback) = &cli.fallback {
+                writeln!(out, "{}", fallback)?;
+            }
+        }
+        if cli.json {
+            writeln!(out, "{}", fortunes_to_json(&picked, cli)?)?;
+        }
+        for (i, fortune) in picked.into_iter().enumerate() {
+            if !cli.json {
+                write_separated(out, &format(fortune), i, cli)?;
+            }
+            if let Some(path) = &cli.log {
+                log_selection(path, fortune, cli.seed)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+        }
+    } else {
+        let fortune = if let Some(path) = &cli.round_robin_state {
+            let err = |e: io::Error| format!("{}: {}", path.display(), e);
+            let last = fs::read_to_string(path)
+                .ok()
+                .and_then(|s| s.trim().parse().ok());
+            let next = next_round_robin(last, fortunes.len());
+            if let Some(i) = next {
+                fs::write(path, format!("{}\n", i)).map_err(err)?;
+            }
+            next.map(|i| &fortunes[i])
+        } else if let Some(path) = &cli.shown_file {
+            let err = |e: io::Error| format!("{}: {}", path.display(), e);
+            let shown = read_shown_ids(path);
+            let (fortune, reset) = pick_unshown(&fortunes, &shown, &mut rng_from_seed(cli.seed));
+            if let Some(fortune) = fortune {
+                let mut file = File::options()
+                    .create(true)
+                    .append(!reset)
+                    .write(true)
+                    .truncate(reset)
+                    .open(path)
+                    .map_err(err)?;
+                writeln!(file, "{:016x}", fortune.id()).map_err(err)?;
+            }
+            fortune
+        } else if source_weights.is_empty() {
+            pick_fortune_full(&fortunes, cli.seed)
+        } else {
+            let weights = fortune_weights(&fortunes, &source_weights);
+            pick_weighted(&fortunes, &weights, 1, &mut rng_from_seed(cli.seed)).pop()
+        };
+        let mut text = match fortune {
+            None if cli.fail_on_empty => return Err("No fortunes found".into()),
+            _ if cli.json => fortune_to_json(fortune, cli)?,
+            Some(fortune) if cli.print_source_only => fortune.source.clone(),
+            Some(fortune) => format(fortune),
+            None => cli
+                .fallback
+                .clone()
+                .unwrap_or_else(|| "No fortunes found".to_string()),
+        };
+        if let (Some(path), Some(fortune)) = (&cli.log, fortune) {
+            log_selection(path, fortune, cli.seed)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        if let Some(fortune) = fortune.filter(|_| cli.with_count) {
+            let position = fortunes.iter().position(|f| std::ptr::eq(f, fortune));
+            text += &format!("\n[{}/{}]", position.unwrap_or(0) + 1, fortunes.len());
+        }
+        if let Some(fortune) = fortune.filter(|_| cli.show_probability) {
+            let position = fortunes.iter().position(|f| std::ptr::eq(f, fortune));
+            let weights = fortune_weights(&fortunes, &source_weights);
+            let probability = selection_probability(&weights, position.unwrap_or(0));
+            eprintln!("Probability: {:.4}", probability);
+        }
+        writeln!(out, "{}", text)?;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        fs::File,
+        io::{self, Read, Write},
+        path::{Path, PathBuf},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use clap::Parser;
+
+    use super::{
+        alpha_ratio, apply_source_directives, art_ratio, balance_weights, common_dir, daily_seed,
+        dedent, dedup_fortunes, detect_eol, dir_tag, dump_delimited, exclude_art_sources,
+        export_json, find_files, first_sentence, fortune_weights, has_short_line, hourly_seed,
+        next_round_robin, output_writer, pack_fortunes, parse_aliases, parse_duration, parse_range,
+        parse_rename, pick_fortune_full, pick_reseeded, pick_unshown, pick_weighted, read_fortunes,
+        read_fortunes_with, read_with_timeout, render_template, rng_from_seed, run_to,
+        sample_sources, scramble_lines, seed_from_str, selection_probability, straight_quotes,
+        walk_entries, Cli, Encoding, FindOptions, Fortune, ParseWarnings, ReadOptions, RetryReader,
+    };
+
+    #[test]
+    fn test_find_files() {
+        let res = find_files(&["./tests/inputs/jokes".to_string()], &Default::default());
+        assert!(res.is_ok());
+
+        let files = res.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files.first().unwrap().to_string_lossy(),
+            "./tests/inputs/jokes"
+        );
+
+        // 存在しないファイルは失敗する
+        let res = find_files(&["/path/does/not/exist".to_string()], &Default::default());
+        assert!(res.is_err());
+
+        // 拡張子が .dat 以外の入力ファイルをすべて検索する
+        let res = find_files(&["./tests/inputs".to_string()], &Default::default());
+        assert!(res.is_ok());
+        let files = res.unwrap();
+        assert_eq!(files.len(), 5);
+        let first = files.first().unwrap().display().to_string();
+        assert!(first.contains("ascii-art"));
+        let last = files.last().unwrap().display().to_string();
+        assert!(last.contains("quotes"));
+
+        // 複数のソースに対するテスト
+        // パスは重複無しでソートされた状態である
+        let res = find_files(
+            &[
+                "./tests/inputs/jokes".to_string(),
+                "./tests/inputs/ascii-art".to_string(),
+                "./tests/inputs/jokes".to_string(),
+            ],
+            &Default::default(),
+        );
+        assert!(res.is_ok());
+        let files = res.unwrap();
+        assert_eq!(files.len(), 2);
+        if let Some(filename) = files.first().unwrap().file_name() {
+            assert_eq!(filename.to_string_lossy(), "ascii-art".to_string());
+        }
+        if let Some(filename) = files.last().unwrap().file_name() {
+            assert_eq!(filename.to_string_lossy(), "jokes".to_string());
+        }
+    }
+
+    #[test]
+    fn test_find_files_skip_binary() {
+        // バイナリファイルはフラグ指定時のみ除外される
+        let paths = [
+            "./tests/fixtures/binary".to_string(),
+            "./tests/inputs/jokes".to_string(),
+        ];
+        assert_eq!(find_files(&paths, &Default::default()).unwrap().len(), 2);
+        let opts = FindOptions {
+            skip_binary: true,
+            ..Default::default()
+        };
+        let files = find_files(&paths, &opts).unwrap();
+        assert_eq!(files, vec![PathBuf::from("./tests/inputs/jokes")]);
+    }
+
+    #[test]
+    fn test_read_fortunes() {
+        // 入力ファイルが1つだけの場合
+        let res = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]);
+        assert!(res.is_ok());
+
+        if let Ok(fortunes) = res {
+            assert_eq!(fortunes.len(), 6);
+            assert_eq!(
                 fortunes.first().unwrap().text,
                 "Q. What do you call a head of lettuce in a shirt and tie?\n\
                 A. Collared greens."
@@ -216,37 +2652,1160 @@ mod tests {
             );
         }
 
-        // 入力ファイルが複数の場合
-        let res = read_fortunes(&[
+        // 入力ファイルが複数の場合
+        let res = read_fortunes(&[
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ]);
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_read_fortunes_counts() {
+        // ファイルごとの件数は読み込みと同時に数える
+        let mut counts = vec![];
+        let res = read_fortunes_with(
+            &[
+                PathBuf::from("./tests/fixtures/blank"),
+                PathBuf::from("./tests/inputs/jokes"),
+                PathBuf::from("./tests/inputs/quotes"),
+            ],
+            ReadOptions {
+                counts: Some(&mut counts),
+                ..Default::default()
+            },
+        );
+        assert_eq!(res.unwrap().len(), 11);
+        assert_eq!(counts, [0, 6, 5]);
+    }
+
+    #[test]
+    fn test_read_fortunes_progress() {
+        // ファイルごとに進捗が出力される
+        let mut progress = vec![];
+        let res = read_fortunes_with(
+            &[
+                PathBuf::from("./tests/inputs/jokes"),
+                PathBuf::from("./tests/inputs/quotes"),
+            ],
+            ReadOptions {
+                progress: Some(&mut progress),
+                ..Default::default()
+            },
+        );
+        assert_eq!(res.unwrap().len(), 11);
+        assert_eq!(
+            String::from_utf8(progress).unwrap(),
+            "\r1/2 files\r2/2 files\n"
+        );
+    }
+
+    #[test]
+    fn test_pick_fortune() {
+        let fortunes = &[
+            Fortune {
+                source: "fortunes".to_string(),
+                text: "You cannot achieve the impossible without \
+                attempting the absurd."
+                    .to_string(),
+                tag: None,
+            },
+            Fortune {
+                source: "fortunes".to_string(),
+                text: "Assumption is the mother of all screw-apps.".to_string(),
+                tag: None,
+            },
+            Fortune {
+                source: "fortunes".to_string(),
+                text: "Neckties strangle clear thinking.".to_string(),
+                tag: None,
+            },
+        ];
+
+        assert_eq!(
+            pick_fortune_full(fortunes, Some(1)).unwrap().text,
+            "Neckties strangle clear thinking.",
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ])
+        .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("fortuner-export-{}.json", std::process::id()));
+
+        assert!(export_json(&fortunes, &path).is_ok());
+        let exported: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 書き出した JSON を読み直すと元の fortune と一致する
+        let items = exported.as_array().unwrap();
+        assert_eq!(items.len(), fortunes.len());
+        for (item, fortune) in items.iter().zip(&fortunes) {
+            assert_eq!(
+                item.get("source").and_then(|v| v.as_str()),
+                Some(fortune.source.as_str())
+            );
+            assert_eq!(
+                item.get("text").and_then(|v| v.as_str()),
+                Some(fortune.text.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn test_seed_from_str() {
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]).unwrap();
+        let pick = |key| {
+            pick_fortune_full(&fortunes, Some(seed_from_str("hello", key)))
+                .unwrap()
+                .text
+                .as_str()
+        };
+
+        // 同じキーなら常に同じ fortune を選ぶ
+        assert_eq!(seed_from_str("hello", 1), seed_from_str("hello", 1));
+        assert_eq!(pick(1), pick(1));
+        assert_eq!(pick(2), pick(2));
+
+        // キーが異なれば別の fortune に対応する
+        assert_ne!(seed_from_str("hello", 1), seed_from_str("hello", 2));
+        assert_ne!(pick(1), pick(2));
+    }
+
+    #[test]
+    fn test_sample_sources() {
+        let files = find_files(&["./tests/inputs".to_string()], &Default::default()).unwrap();
+
+        // 重複のない N 個のファイルが選ばれる
+        let sampled = sample_sources(&files, 3, Some(1));
+        assert_eq!(sampled.len(), 3);
+        let mut distinct = sampled.clone();
+        distinct.dedup();
+        assert_eq!(distinct, sampled);
+        assert!(sampled.iter().all(|f| files.contains(f)));
+
+        // 同じシードなら同じファイルが選ばれる
+        assert_eq!(sample_sources(&files, 3, Some(1)), sampled);
+
+        // ファイル数より大きい N はすべてのファイルを選ぶ
+        assert_eq!(sample_sources(&files, 10, Some(1)), files);
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ])
+        .unwrap();
+        let weights = fortune_weights(
+            &fortunes,
+            &[("jokes".to_string(), 100.0), ("quotes".to_string(), 1.0)],
+        );
+        let picked = pick_weighted(&fortunes, &weights, 5, &mut rng_from_seed(Some(1)));
+
+        // 重複なく N 個選ばれる
+        assert_eq!(picked.len(), 5);
+        for (i, a) in picked.iter().enumerate() {
+            assert!(picked[i + 1..].iter().all(|b| a.text != b.text));
+        }
+
+        // 重みの大きいソースに偏る
+        let jokes = picked.iter().filter(|f| f.source == "jokes").count();
+        assert_eq!(jokes, 5);
+
+        // 同じシードなら同じ結果になる
+        let again = pick_weighted(&fortunes, &weights, 5, &mut rng_from_seed(Some(1)));
+        assert!(picked.iter().zip(&again).all(|(a, b)| a.text == b.text));
+
+        // プールより多くは選べない
+        let all = pick_weighted(&fortunes, &weights, 20, &mut rng_from_seed(Some(1)));
+        assert_eq!(all.len(), fortunes.len());
+    }
+
+    #[test]
+    fn test_balance_weights() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/fixtures/tiny"),
+        ])
+        .unwrap();
+        let weights = fortune_weights(&fortunes, &balance_weights(&fortunes, &[]));
+
+        // 大きいソースと小さいソースがほぼ同じ割合で選ばれる
+        let mut rng = rng_from_seed(Some(1));
+        let tiny = (0..1000)
+            .filter(|_| pick_weighted(&fortunes, &weights, 1, &mut rng)[0].source == "tiny")
+            .count();
+        assert!((450..=550).contains(&tiny), "{}", tiny);
+
+        // 明示した重みはソースごとの重みに掛け合わされる
+        let weighted = balance_weights(&fortunes, &[("tiny".to_string(), 3.0)]);
+        assert_eq!(weighted.iter().find(|(s, _)| s == "tiny").unwrap().1, 3.0);
+        assert!(weighted.iter().any(|(s, w)| s == "jokes" && *w < 1.0));
+    }
+
+    #[test]
+    fn test_apply_source_directives() {
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/fixtures/directives")]).unwrap();
+        let fortunes = apply_source_directives(fortunes);
+
+        // 先頭行の指定があればソース名を上書きし、その行を取り除く
+        let pairs: Vec<_> = fortunes
+            .iter()
+            .map(|f| (f.source.as_str(), f.text.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("wisdom", "A penny saved is a penny earned."),
+                ("directives", "There is no directive on this one."),
+                ("puns", "I used to be a banker, but I lost interest."),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        let fortune = Fortune {
+            source: "fortunes".to_string(),
+            text: "Neckties strangle clear thinking.".to_string(),
+            tag: None,
+        };
+
+        assert_eq!(
+            render_template("[{source}] {length} chars\n{text}", &fortune, &fortune.text),
+            "[fortunes] 33 chars\nNeckties strangle clear thinking."
+        );
+        // 未知のプレースホルダーはそのまま残す
+        assert_eq!(
+            render_template("{unknown}", &fortune, &fortune.text),
+            "{unknown}"
+        );
+        // 加工したテキストを渡しても id は元の fortune のもの
+        assert_eq!(
+            render_template("{id} {text}", &fortune, "Neckties."),
+            format!("{:016x} Neckties.", fortune.id())
+        );
+        // タグのない fortune では {tag} は空になる
+        assert_eq!(render_template("[{tag}]", &fortune, &fortune.text), "[]");
+    }
+
+    #[test]
+    fn test_prepare() {
+        let output = |args: &[&str]| {
+            let mut cli = Cli::try_parse_from([&["fortuner"], args].concat()).unwrap();
+            cli.validate().unwrap();
+            cli.prepare().unwrap();
+            let mut out = vec![];
+            run_to(&cli, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // get_cli を通さずに作った Cli でもパターンが効く
+        assert_eq!(
+            output(&["-m", "Yogi Berra", "-s", "1", "./tests/inputs/jokes"]),
+            ""
+        );
+        assert_eq!(
+            output(&["-m", "yogi", "-i", "./tests/inputs/quotes"])
+                .matches("Yogi")
+                .count(),
+            2
+        );
+        assert_eq!(
+            output(&[
+                "--template-file",
+                "./tests/fixtures/template",
+                "./tests/fixtures/tiny"
+            ]),
+            output(&[
+                "--template",
+                std::fs::read_to_string("./tests/fixtures/template")
+                    .unwrap()
+                    .trim_end(),
+                "./tests/fixtures/tiny"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |args: &[&str]| {
+            Cli::try_parse_from([&["fortuner", "./tests/inputs"], args].concat())
+                .unwrap()
+                .validate()
+                .map_err(|e| e.to_string())
+        };
+
+        assert!(validate(&[]).is_ok());
+        assert!(validate(&["-m", "Yogi", "-v", "--all-sources"]).is_ok());
+
+        // 範囲外の値
+        assert_eq!(
+            validate(&["--count-out", "0"]).unwrap_err(),
+            "--count-out must be greater than 0"
+        );
+        assert_eq!(
+            validate(&["--sample-sources", "0"]).unwrap_err(),
+            "--sample-sources must be greater than 0"
+        );
+
+        // 組み合わせられないオプション
+        assert_eq!(
+            validate(&["-v"]).unwrap_err(),
+            "--invert-match requires --pattern"
+        );
+        assert_eq!(
+            validate(&["-m", "Yogi", "--count-out", "2"]).unwrap_err(),
+            "--count-out cannot be used with --pattern"
+        );
+        assert_eq!(
+            validate(&["--print-source-only", "--count-out", "2"]).unwrap_err(),
+            "--print-source-only cannot be used with --count-out"
+        );
+        assert_eq!(
+            validate(&["--silent-fail"]).unwrap_err(),
+            "--silent-fail requires --pattern"
+        );
+        assert_eq!(
+            validate(&["-m", "Yogi", "--silent-fail", "--no-match-exit-code", "3"]).unwrap_err(),
+            "--silent-fail cannot be used with --fallback or --no-match-exit-code"
+        );
+        assert_eq!(
+            validate(&["--warn-source-count", "3"]).unwrap_err(),
+            "--warn-source-count requires --stats"
+        );
+        assert_eq!(
+            validate(&["--show-probability", "--count-out", "2"]).unwrap_err(),
+            "--show-probability cannot be used with --pattern, --count-out, \
+             --round-robin-state or --shown-file"
+        );
+        assert_eq!(
+            validate(&["--seed", "1", "--os-random"]).unwrap_err(),
+            "--os-random cannot be used with --seed, --seed-from, --seed-base or --at-hour"
+        );
+        assert!(validate(&["--stats", "--warn-source-count", "3"]).is_ok());
+        assert_eq!(
+            validate(&["-m", "Yogi", "-v", "--show-pattern"]).unwrap_err(),
+            "--show-pattern cannot be used with --invert-match"
+        );
+        // 同時に指定できないモード
+        assert_eq!(
+            validate(&["-f", "--stats"]).unwrap_err(),
+            "--files cannot be used with --stats"
+        );
+        assert_eq!(
+            validate(&["--export-json", "out.json", "-m", "Yogi"]).unwrap_err(),
+            "--export-json cannot be used with --pattern"
+        );
+        assert!(validate(&["--summary", "-m", "Yogi"]).is_ok());
+        assert_eq!(
+            validate(&["--with-count", "--count-out", "2"]).unwrap_err(),
+            "--with-count cannot be used with --count-out"
+        );
+        assert_eq!(
+            validate(&["--log", "picked.log", "-m", "Yogi"]).unwrap_err(),
+            "--log cannot be used with --pattern"
+        );
+        assert!(validate(&["--log", "picked.log", "--count-out", "2"]).is_ok());
+        assert_eq!(
+            validate(&["--rename", "s/a/b/"]).unwrap_err(),
+            "--rename requires --pattern"
+        );
+        assert_eq!(
+            validate(&["--absolute"]).unwrap_err(),
+            "--absolute requires --files, --dump-offsets or --detect-eol"
+        );
+        assert!(validate(&["--absolute", "-f"]).is_ok());
+        assert_eq!(
+            validate(&["--json", "--pack", "60"]).unwrap_err(),
+            "--json cannot be used with --pack"
+        );
+        assert_eq!(
+            validate(&["--json", "--with-count"]).unwrap_err(),
+            "--json cannot be used with --with-count"
+        );
+        assert!(validate(&["--json", "--count-out", "2"]).is_ok());
+        assert_eq!(
+            validate(&["-m", "Yogi", "--json", "--show-pattern"]).unwrap_err(),
+            "--json cannot be used with --show-pattern, --all-sources, --fallback or --rename"
+        );
+        // gitignore フィーチャーなしでは --respect-gitignore を使えない
+        assert_eq!(
+            validate(&["--respect-gitignore"]).is_ok(),
+            cfg!(feature = "gitignore")
+        );
+    }
+
+    #[test]
+    fn test_exclude_art_sources() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/inputs/ascii-art"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ])
+        .unwrap();
+        let ratio = |source| {
+            art_ratio(
+                fortunes
+                    .iter()
+                    .filter(|f| f.source == source)
+                    .map(|f| f.text.as_str()),
+            )
+        };
+        assert!(ratio("ascii-art") > 0.9);
+        assert!(ratio("quotes") < 0.1);
+        assert_eq!(art_ratio([]), 0.0);
+
+        // アスキーアートのソースだけが除外される
+        let fortunes = exclude_art_sources(fortunes);
+        assert_eq!(fortunes.len(), 5);
+        assert!(fortunes.iter().all(|f| f.source == "quotes"));
+    }
+
+    #[test]
+    fn test_scramble_lines() {
+        let text = "one\ntwo\nthree\nfour\nfive";
+        let scrambled = scramble_lines(text, &mut rng_from_seed(Some(1)));
+
+        // 同じシードなら同じ並び順になる
+        assert_eq!(scrambled, scramble_lines(text, &mut rng_from_seed(Some(1))));
+        assert_ne!(scrambled, text);
+
+        // 元の行の並べ替えである
+        let mut lines: Vec<_> = scrambled.lines().collect();
+        lines.sort();
+        let mut expected: Vec<_> = text.lines().collect();
+        expected.sort();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_has_short_line() {
+        let art = "  /\\_/\\\n ( o.o )\n  > ^ <";
+        let prose = "You can observe a lot just by watching.\n\n-- Yogi Berra";
+
+        assert!(has_short_line(art, 5));
+        assert!(!has_short_line(art, 3));
+        // 空行は無視する
+        assert!(!has_short_line(prose, 5));
+        assert!(has_short_line(prose, 12));
+    }
+
+    #[test]
+    fn test_read_fortunes_cached() {
+        let paths = [
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ];
+        let cache_dir = std::env::temp_dir().join(format!("fortuner-cache-{}", std::process::id()));
+        let read = || {
+            read_fortunes_with(
+                &paths,
+                ReadOptions {
+                    cache_dir: Some(&cache_dir),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        // インデックスを作成し、通常の読み込みと同じ結果になる
+        let expected = read_fortunes(&paths).unwrap();
+        let fortunes = read();
+        assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 2);
+        let texts =
+            |fortunes: &[Fortune]| fortunes.iter().map(|f| f.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts(&fortunes), texts(&expected));
+
+        assert_eq!(
+            pick_fortune_full(&fortunes, Some(1)).unwrap().text,
+            pick_fortune_full(&expected, Some(1)).unwrap().text
+        );
+
+        // 2 回目以降は作成済みのインデックスが使われる
+        for entry in std::fs::read_dir(&cache_dir).unwrap() {
+            std::fs::write(entry.unwrap().path(), "0\t18\n").unwrap();
+        }
+        let fortunes = read();
+        assert_eq!(
+            texts(&fortunes),
+            ["Q. What do you cal", "Twenty years from "]
+        );
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_fortunes_max_bytes() {
+        let read = |path: &str, n| {
+            read_fortunes_with(
+                &[PathBuf::from(path)],
+                ReadOptions {
+                    max_fortune_bytes: Some(n),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // 区切りのないファイルは上限を超えるとエラーになる
+        let res = read("./tests/fixtures/no-delimiters", 1000);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "no-delimiters: fortune exceeds 1000 bytes"
+        );
+
+        // 上限ちょうどの fortune は読み込める
+        assert_eq!(read("./tests/inputs/quotes", 249).unwrap().len(), 5);
+        assert!(read("./tests/inputs/quotes", 248).is_err());
+    }
+
+    #[test]
+    fn test_read_fortunes_max_total() {
+        let paths = [
             PathBuf::from("./tests/inputs/jokes"),
             PathBuf::from("./tests/inputs/quotes"),
-        ]);
-        assert!(res.is_ok());
-        assert_eq!(res.unwrap().len(), 11);
+        ];
+        let read = |max_total| {
+            read_fortunes_with(
+                &paths,
+                ReadOptions {
+                    max_total,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        // 探索順に N 件集まったところで読み込みをやめる
+        let fortunes = read(Some(8));
+        assert_eq!(fortunes.len(), 8);
+        assert_eq!(fortunes[5].source, "jokes");
+        assert_eq!(fortunes[7].source, "quotes");
+        assert_eq!(read(Some(3)).len(), 3);
+        assert_eq!(read(Some(100)).len(), 11);
     }
 
     #[test]
-    fn test_pick_fortune() {
-        let fortunes = &[
-            Fortune {
-                source: "fortunes".to_string(),
-                text: "You cannot achieve the impossible without \
-                attempting the absurd."
-                    .to_string(),
+    fn test_read_with_timeout() {
+        struct Stalled;
+
+        impl Read for Stalled {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_secs(60));
+                Ok(0)
+            }
+        }
+
+        // 時間内にデータが来なければ読み込みを諦める
+        let mut bytes = vec![];
+        let err = read_with_timeout(Stalled, Duration::from_millis(10))
+            .read_to_end(&mut bytes)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        struct Trickle(usize);
+
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Ok(0);
+                }
+                self.0 -= 1;
+                thread::sleep(Duration::from_millis(50));
+                buf[0] = b'x';
+                Ok(1)
+            }
+        }
+
+        // データが届き続ける限り、全体で時間を超えても読み込みを続ける
+        let mut bytes = vec![];
+        read_with_timeout(Trickle(6), Duration::from_millis(200))
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, b"xxxxxx");
+
+        let mut bytes = vec![];
+        let file = File::open("./tests/inputs/jokes").unwrap();
+        read_with_timeout(file, Duration::from_secs(10))
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, std::fs::read("./tests/inputs/jokes").unwrap());
+    }
+
+    #[test]
+    fn test_read_fortunes_warnings() {
+        let mut warnings = ParseWarnings::default();
+        let fortunes = read_fortunes_with(
+            &[PathBuf::from("./tests/fixtures/fixups")],
+            ReadOptions {
+                warnings: Some(&mut warnings),
+                ..Default::default()
             },
-            Fortune {
-                source: "fortunes".to_string(),
-                text: "Assumption is the mother of all screw-apps.".to_string(),
+        )
+        .unwrap();
+
+        // 区切りのない末尾の fortune も読み込まれる
+        let texts: Vec<&str> = fortunes.iter().map(|f| f.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            ["First fortune.", "The last fortune has no delimiter."]
+        );
+        assert_eq!(
+            warnings,
+            ParseWarnings {
+                empty_records: 2,
+                unterminated_records: 1,
+                crlf_lines: 2,
+            }
+        );
+
+        let mut warnings = ParseWarnings::default();
+        read_fortunes_with(
+            &[PathBuf::from("./tests/inputs/quotes")],
+            ReadOptions {
+                warnings: Some(&mut warnings),
+                ..Default::default()
             },
-            Fortune {
-                source: "fortunes".to_string(),
-                text: "Neckties strangle clear thinking.".to_string(),
+        )
+        .unwrap();
+        assert_eq!(warnings, ParseWarnings::default());
+    }
+
+    #[test]
+    fn test_retry_reader() {
+        let jokes = std::fs::read("./tests/inputs/jokes").unwrap();
+
+        // 途中で読み込みに失敗するリーダー
+        struct Failing(io::Cursor<Vec<u8>>);
+
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.read(&mut buf[..1])? {
+                    0 => Err(io::Error::other("flaky")),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        // 最初の K 回は開けず、次の 1 回は途中で失敗する
+        let flaky = |failures: usize| {
+            let mut attempts = 0;
+            let jokes = jokes.clone();
+            move || -> io::Result<Box<dyn Read>> {
+                attempts += 1;
+                if attempts <= failures {
+                    Err(io::Error::new(io::ErrorKind::Interrupted, "flaky"))
+                } else if attempts == failures + 1 {
+                    Ok(Box::new(Failing(io::Cursor::new(jokes[..10].to_vec()))))
+                } else {
+                    Ok(Box::new(io::Cursor::new(jokes.clone())))
+                }
+            }
+        };
+        let read = |failures, retries| {
+            let mut bytes = vec![];
+            RetryReader::new(flaky(failures), retries, Duration::ZERO)
+                .read_to_end(&mut bytes)
+                .map(|_| bytes)
+        };
+
+        // 開き直して続きから読む
+        assert_eq!(read(2, 3).unwrap(), jokes);
+        assert_eq!(read(0, 5).unwrap(), jokes);
+        assert!(read(2, 2).is_err());
+        assert!(read(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_read_fortunes_file_encoding() {
+        let paths = [
+            PathBuf::from("./tests/fixtures/latin1"),
+            PathBuf::from("./tests/fixtures/utf8"),
+        ];
+        let file_encodings = [("latin1".to_string(), Encoding::Latin1)];
+        let fortunes = read_fortunes_with(
+            &paths,
+            ReadOptions {
+                file_encodings: &file_encodings,
+                ..Default::default()
             },
+        )
+        .unwrap();
+
+        // 指定したファイルだけ Latin-1 として読み、他は UTF-8 のまま
+        assert_eq!(fortunes.len(), 2);
+        assert!(fortunes
+            .iter()
+            .all(|f| f.text == "Un café crème, s'il vous plaît."));
+    }
+
+    #[test]
+    fn test_dedent() {
+        assert_eq!(
+            dedent("    def f():\n        return 1\n\n    f()"),
+            "def f():\n    return 1\n\nf()"
+        );
+        // タブとスペースが混在する場合は共通部分だけを取り除く
+        assert_eq!(dedent("\t  a\n\t b"), " a\nb");
+        assert_eq!(dedent("a\n  b"), "a\n  b");
+    }
+
+    #[test]
+    fn test_first_sentence() {
+        assert_eq!(
+            first_sentence("Hello, world. This is a test! Is it?"),
+            "Hello, world."
+        );
+        // 単語の途中のピリオドでは区切らない
+        assert_eq!(
+            first_sentence("See e.g.this one? Yes."),
+            "See e.g.this one?"
+        );
+        assert_eq!(first_sentence("Wait!\nThere is more."), "Wait!");
+        assert_eq!(first_sentence("No punctuation"), "No punctuation");
+    }
+
+    #[test]
+    fn test_pack_fortunes() {
+        let fortunes: Vec<Fortune> = ["Short one.", "Another.", "A rather longer fortune."]
+            .into_iter()
+            .map(|text| Fortune {
+                source: "short".to_string(),
+                text: text.to_string(),
+                tag: None,
+            })
+            .collect();
+
+        // 短い2つは1ブロックにまとまり、次は収まらないので別ブロックになる
+        assert_eq!(
+            pack_fortunes(&fortunes, 20),
+            ["Short one. Another.", "A rather longer fortune."]
+        );
+        assert_eq!(pack_fortunes(&fortunes, 5).len(), 3);
+        assert_eq!(pack_fortunes(&fortunes, 100).len(), 1);
+    }
+
+    #[test]
+    fn test_daily_seed() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let date = SystemTime::UNIX_EPOCH + day * 20000 + Duration::from_secs(5 * 60 * 60);
+
+        // 同じ日の中ではシードが変わらず、翌日に1つ進む
+        assert_eq!(daily_seed(7, date), 20007);
+        assert_eq!(daily_seed(7, date + Duration::from_secs(60 * 60)), 20007);
+        assert_eq!(daily_seed(7, date + day), 20008);
+
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]).unwrap();
+        assert_eq!(
+            pick_fortune_full(&fortunes, Some(daily_seed(7, date)))
+                .unwrap()
+                .text,
+            "Q. Why did the honeydew couple get married in a church?\n\
+            A. Their parents told them they cantaloupe."
+        );
+    }
+
+    #[test]
+    fn test_hourly_seed() {
+        let hour = Duration::from_secs(60 * 60);
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(24 * 60 * 60) * 20000 + hour * 5;
+
+        // 同じ時間帯の中ではシードが変わらず、次の時間に変わる
+        assert_eq!(hourly_seed(date), 2024100405);
+        assert_eq!(hourly_seed(date + hour / 2), 2024100405);
+        assert_eq!(hourly_seed(date + hour), 2024100406);
+        assert_eq!(hourly_seed(date + hour * 19), 2024100500);
+        assert_eq!(hourly_seed(SystemTime::UNIX_EPOCH), 1970010100);
+
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]).unwrap();
+        let pick = |now| pick_fortune_full(&fortunes, Some(hourly_seed(now))).unwrap();
+        assert_eq!(pick(date).text, pick(date + hour / 2).text);
+        assert_ne!(pick(date).text, pick(date + hour).text);
+    }
+
+    #[test]
+    fn test_pick_unshown() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/fixtures/nested/animals/pets"),
+            PathBuf::from("./tests/fixtures/nested/plants/trees"),
+        ])
+        .unwrap();
+        let mut shown = HashSet::new();
+        let mut rng = rng_from_seed(Some(1));
+
+        // 全部表示し終えるまでは重複しない
+        for _ in 0..fortunes.len() {
+            let (fortune, reset) = pick_unshown(&fortunes, &shown, &mut rng);
+            assert!(!reset);
+            assert!(shown.insert(fortune.unwrap().id()));
+        }
+
+        // 全部表示したら最初からやり直す
+        let (fortune, reset) = pick_unshown(&fortunes, &shown, &mut rng);
+        assert!(reset);
+        assert!(fortune.is_some());
+
+        let (fortune, _) = pick_unshown(&[], &shown, &mut rng);
+        assert!(fortune.is_none());
+    }
+
+    #[test]
+    fn test_selection_probability() {
+        let fortunes = read_fortunes(&[
+            PathBuf::from("./tests/inputs/jokes"),
+            PathBuf::from("./tests/inputs/quotes"),
+        ])
+        .unwrap();
+        let weights = fortune_weights(&fortunes, &[("jokes".to_string(), 3.0)]);
+
+        // 重み 3 の jokes 6 件と重み 1 の quotes 5 件
+        assert_eq!(selection_probability(&weights, 0), 3.0 / 23.0);
+        assert_eq!(selection_probability(&weights, 10), 1.0 / 23.0);
+        let total: f64 = (0..weights.len())
+            .map(|i| selection_probability(&weights, i))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        assert_eq!(selection_probability(&weights, 11), 0.0);
+        assert_eq!(selection_probability(&[0.0], 0), 0.0);
+    }
+
+    #[test]
+    fn test_next_round_robin() {
+        assert_eq!(next_round_robin(None, 3), Some(0));
+        assert_eq!(next_round_robin(Some(0), 3), Some(1));
+        // 末尾の次は先頭に戻る
+        assert_eq!(next_round_robin(Some(2), 3), Some(0));
+        // プールが縮んでも範囲内に収まる
+        assert_eq!(next_round_robin(Some(7), 3), Some(2));
+        assert_eq!(next_round_robin(Some(0), 0), None);
+    }
+
+    #[test]
+    fn test_pick_reseeded() {
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]).unwrap();
+        let weights = vec![1.0; fortunes.len()];
+        let pick = |n| -> Vec<String> {
+            pick_reseeded(&fortunes, &weights, n, 1)
+                .into_iter()
+                .map(|f| f.text.clone())
+                .collect()
+        };
+
+        // 同じシードなら毎回同じ並びになり、重複もしない
+        let picked = pick(4);
+        assert_eq!(picked, pick(4));
+        assert_eq!(picked.iter().collect::<HashSet<_>>().len(), 4);
+
+        // 先頭の出力は出力数に依存しない
+        assert_eq!(pick(1)[0], picked[0]);
+        assert_eq!(pick(6)[..4], picked[..]);
+    }
+
+    #[test]
+    fn test_straight_quotes() {
+        assert_eq!(
+            straight_quotes("\u{201c}Don\u{2019}t panic,\u{201d} he said. \u{2018}OK.\u{2019}"),
+            "\"Don't panic,\" he said. 'OK.'"
+        );
+        assert_eq!(straight_quotes("Plain \"text\""), "Plain \"text\"");
+    }
+
+    #[test]
+    fn test_alpha_ratio() {
+        let art = "  /\\_/\\\n ( o.o )\n  > ^ <";
+        let prose = "Q: Why did the gardener quit his job?\nA: His celery wasn't high enough.";
+
+        // 記号だけのアスキーアートは 0.5 を下回り、文章は上回る
+        assert!(alpha_ratio(art) < 0.5);
+        assert!(alpha_ratio(prose) > 0.5);
+        assert_eq!(alpha_ratio("abc!"), 0.75);
+        assert_eq!(alpha_ratio(""), 0.0);
+    }
+
+    #[test]
+    fn test_walk_entries() {
+        let entries = || vec![Ok(1), Err("permission denied"), Ok(2)];
+
+        // 既定ではエラーを無視する
+        assert_eq!(walk_entries(entries(), false).unwrap(), [1, 2]);
+
+        // --fail-fast では最初のエラーを返す
+        assert_eq!(
+            walk_entries(entries(), true).unwrap_err().to_string(),
+            "permission denied"
+        );
+    }
+
+    #[test]
+    fn test_dedup_fortunes() {
+        let fortunes = || {
+            vec![
+                Fortune {
+                    source: "a".to_string(),
+                    text: "Neckties strangle clear thinking.".to_string(),
+                    tag: None,
+                },
+                Fortune {
+                    source: "b".to_string(),
+                    text: "neckties  strangle\nclear THINKING.".to_string(),
+                    tag: None,
+                },
+                Fortune {
+                    source: "b".to_string(),
+                    text: "Neckties strangle clear thinking.".to_string(),
+                    tag: None,
+                },
+            ]
+        };
+
+        // 完全一致だけを重複とみなす
+        let unique = dedup_fortunes(fortunes(), false, &[]);
+        assert_eq!(unique.len(), 2);
+        assert_eq!(unique[0].source, "a");
+
+        // 正規化すると大文字小文字と空白の違いを無視する
+        let unique = dedup_fortunes(fortunes(), true, &[]);
+        assert_eq!(unique.len(), 1);
+        assert_eq!(unique[0].source, "a");
+
+        // 優先度の高いソースのものを残す
+        let priority = ["b".to_string(), "a".to_string()];
+        let unique = dedup_fortunes(fortunes(), false, &priority);
+        assert_eq!(unique.len(), 2);
+        assert_eq!(unique[0].source, "b");
+        assert_eq!(unique[1].source, "b");
+        assert_eq!(unique[1].text, "Neckties strangle clear thinking.");
+    }
+
+    #[test]
+    fn test_find_files_max_age() {
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604800)));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("xd").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+
+        let dir = std::env::temp_dir().join(format!("fortuner-age-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ten_days = Duration::from_secs(10 * 86400);
+        for (name, age) in [("old", ten_days), ("new", Duration::ZERO)] {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - age).unwrap();
+        }
+
+        // 指定した期間内に更新されたファイルだけが対象になる
+        let opts = FindOptions {
+            max_age: parse_duration("7d").ok(),
+            ..Default::default()
+        };
+        let files = find_files(&[dir.display().to_string()], &opts).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, [dir.join("new")]);
+    }
+
+    #[test]
+    fn test_find_files_min_file_bytes() {
+        let paths = [
+            "./tests/fixtures/blank".to_string(),
+            "./tests/fixtures/tiny".to_string(),
+            "./tests/inputs/jokes".to_string(),
         ];
+        let find = |min_file_bytes| {
+            let opts = FindOptions {
+                min_file_bytes,
+                ..Default::default()
+            };
+            find_files(&paths, &opts).unwrap()
+        };
+
+        assert_eq!(find(None).len(), 3);
+        // 小さすぎるファイルは読み込む前に除外される
+        assert_eq!(find(Some(6)).len(), 2);
+        assert_eq!(find(Some(16)), [PathBuf::from("./tests/inputs/jokes")]);
+    }
 
+    #[test]
+    fn test_output_writer() {
+        let cli = Cli::parse_from(["fortuner", "--buffer-size", "16", "./tests/inputs"]);
+        let mut inner = vec![];
+        let mut out = output_writer(&mut inner, &cli);
+        assert_eq!(out.inner.inner.capacity(), 16);
+
+        // 容量に収まる書き込みはバッファに溜まる
+        out.write_all(b"0123456789").unwrap();
+        assert!(out.inner.inner.get_ref().is_empty());
+        out.write_all(b"0123456789").unwrap();
+        out.flush().unwrap();
+        drop(out);
+        assert_eq!(inner, b"01234567890123456789");
+
+        let cli = Cli::parse_from(["fortuner", "./tests/inputs"]);
         assert_eq!(
-            pick_fortune(fortunes, Some(1)).unwrap(),
-            "Neckties strangle clear thinking.",
+            output_writer(vec![], &cli).inner.inner.capacity(),
+            64 * 1024
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("70-80"), Ok(70..=80));
+        assert_eq!(parse_range("5-5"), Ok(5..=5));
+        assert!(parse_range("80-70").is_err());
+        assert!(parse_range("70").is_err());
+        assert!(parse_range("-80").is_err());
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        let rename = parse_rename("s/_/ /g").unwrap();
+        assert_eq!(rename.apply("dad_jokes_2"), "dad jokes 2");
+        let rename = parse_rename("s|^([a-z]+)_.*|$1|").unwrap();
+        assert_eq!(rename.apply("dad_jokes_2"), "dad");
+        // g がなければ最初の一致だけを置換する
+        assert_eq!(parse_rename("s/_/-/").unwrap().apply("a_b_c"), "a-b_c");
+
+        assert!(parse_rename("s/_/ ").is_err());
+        assert!(parse_rename("s/_/ /x").is_err());
+        assert!(parse_rename("y/_/ /").is_err());
+        assert!(parse_rename("s/(/ /").is_err());
+    }
+
+    #[test]
+    fn test_parse_aliases() {
+        let aliases = parse_aliases("# comment\njokes = ../inputs/jokes\n\nq=quotes\n").unwrap();
+        assert_eq!(
+            aliases,
+            [
+                ("jokes".to_string(), "../inputs/jokes".to_string()),
+                ("q".to_string(), "quotes".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            parse_aliases("jokes = a\njokes\n").unwrap_err().to_string(),
+            "line 2: expected NAME = PATH"
+        );
+        assert!(parse_aliases(" = a").is_err());
+    }
+
+    #[test]
+    fn test_detect_eol() {
+        let detect = |path| detect_eol(&std::fs::read(path).unwrap());
+        assert_eq!(detect("./tests/inputs/jokes"), "LF");
+        assert_eq!(detect("./tests/fixtures/crlf"), "CRLF");
+        assert_eq!(detect("./tests/fixtures/mixed-eol"), "mixed");
+        assert_eq!(detect("./tests/fixtures/blank"), "none");
+    }
+
+    #[test]
+    fn test_common_dir() {
+        let paths = [
+            PathBuf::from("/usr/share/games/fortunes/jokes"),
+            PathBuf::from("/usr/share/games/fortunes/off/jokes"),
+            PathBuf::from("/usr/share/games/fortunes/quotes"),
+        ];
+        assert_eq!(
+            common_dir(&paths),
+            Some(PathBuf::from("/usr/share/games/fortunes"))
+        );
+        // ファイルが1つならその親ディレクトリになる
+        assert_eq!(
+            common_dir(&paths[1..2]),
+            Some(PathBuf::from("/usr/share/games/fortunes/off"))
+        );
+        // 標準入力 (-) は共通ディレクトリに含めない
+        let with_stdin = [PathBuf::from("-"), paths[0].clone()];
+        assert_eq!(
+            common_dir(&with_stdin),
+            Some(PathBuf::from("/usr/share/games/fortunes"))
+        );
+        assert_eq!(common_dir(&[]), None);
+    }
+
+    #[test]
+    fn test_dir_tag() {
+        assert_eq!(
+            dir_tag(Path::new("./tests/fixtures/nested/animals/pets")).as_deref(),
+            Some("animals")
+        );
+        assert_eq!(dir_tag(Path::new("jokes")), None);
+    }
+
+    #[test]
+    fn test_dump_delimited() {
+        let fortunes = read_fortunes(&[PathBuf::from("./tests/inputs/jokes")]).unwrap();
+        let mut out = vec![];
+        dump_delimited(&mut out, &fortunes, "%%").unwrap();
+        let dumped = String::from_utf8(out).unwrap();
+
+        // %% 区切りで読み直すと元と同じ fortune になる
+        let texts: Vec<&str> = dumped.split_terminator("\n%%\n").collect();
+        assert_eq!(texts.len(), fortunes.len());
+        assert!(texts.iter().zip(&fortunes).all(|(t, f)| *t == f.text));
+        assert!(!dumped.lines().any(|line| line == "%"));
+    }
+
+    #[cfg(feature = "gitignore")]
+    #[test]
+    fn test_find_files_gitignore() {
+        let dir = std::env::temp_dir().join(format!("fortuner-gitignore-{}", std::process::id()));
+        for (name, contents) in [
+            (".gitignore", "# generated\n*.bak\nbuild/\n/drafts\n"),
+            ("jokes", ""),
+            ("jokes.bak", ""),
+            ("build/jokes", ""),
+            ("drafts", ""),
+            ("sub/drafts", ""),
+            ("sub/.gitignore", "!keep.bak\n"),
+            ("sub/keep.bak", ""),
+            ("sub/old.bak", ""),
+            ("src/.gitignore", "*.[ch]\n\\#notes\n"),
+            ("src/jokes.c", ""),
+            ("src/jokes.h", ""),
+            ("src/#notes", ""),
+            ("src/quotes", ""),
+            (".git/info/exclude", "scratch\n"),
+            ("scratch", ""),
+        ] {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let find_in = |root: &Path, respect_gitignore| {
+            let opts = FindOptions {
+                respect_gitignore,
+                ..Default::default()
+            };
+            let files = find_files(&[root.display().to_string()], &opts).unwrap();
+            files
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let find = |respect_gitignore| find_in(&dir, respect_gitignore);
+
+        assert_eq!(find(false).len(), 16);
+        // .gitignore で除外されたファイルは読み込まない
+        assert_eq!(
+            find(true),
+            ["jokes", "src/quotes", "sub/drafts", "sub/keep.bak"]
+        );
+        // 親ディレクトリの .gitignore も適用される
+        assert_eq!(
+            find_in(&dir.join("sub"), true),
+            ["sub/drafts", "sub/keep.bak"]
         );
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,6 +1,9 @@
 fn main() {
-    if let Err(e) = fortuner::get_cli().and_then(fortuner::run) {
-        eprintln!("{}", e);
-        std::process::exit(1);
+    match fortuner::get_cli().and_then(fortuner::run) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
 }
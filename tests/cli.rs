@@ -1,6 +1,7 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
+use serde_json::Value;
 use std::fs;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
@@ -11,6 +12,8 @@ const EMPTY_DIR: &str = "./tests/inputs/empty";
 const JOKES: &str = "./tests/inputs/jokes";
 const LITERATURE: &str = "./tests/inputs/literature";
 const QUOTES: &str = "./tests/inputs/quotes";
+const DUPLICATES: &str = "./tests/fixtures/duplicates";
+const BLANK: &str = "./tests/fixtures/blank";
 
 fn random_string() -> String {
     rand::thread_rng()
@@ -67,6 +70,41 @@ fn no_fortunes_found() -> TestResult {
     run(&[EMPTY_DIR], "No fortunes found\n")
 }
 
+#[test]
+fn no_fortunes_found_fallback() -> TestResult {
+    run(
+        &[EMPTY_DIR, "--fallback", "Have a nice day."],
+        "Have a nice day.\n",
+    )?;
+    run(
+        &[
+            QUOTES,
+            "-m",
+            "Abraham Lincoln",
+            "--fallback",
+            "Have a nice day.",
+        ],
+        "Have a nice day.\n",
+    )
+}
+
+#[test]
+fn dies_no_fortunes_found() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([EMPTY_DIR, "--fail-on-empty"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr("No fortunes found\n");
+    Command::cargo_bin(PRG)?
+        .args([EMPTY_DIR, "--fail-on-empty", "--count-out", "2"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr("No fortunes found\n");
+    Ok(())
+}
+
 #[test]
 fn quotes_seed_1() -> TestResult {
     run(
@@ -83,6 +121,22 @@ fn jokes_seed_1() -> TestResult {
     )
 }
 
+#[test]
+fn jokes_seed_1_crlf() -> TestResult {
+    run(
+        &[JOKES, "-s", "1", "--line-ending", "crlf"],
+        "Q: What happens when frogs park illegally?\r\nA: They get toad.\r\n",
+    )
+}
+
+#[test]
+fn jokes_seed_1_with_count() -> TestResult {
+    run(
+        &[JOKES, "-s", "1", "--with-count"],
+        "Q: What happens when frogs park illegally?\nA: They get toad.\n[5/6]\n",
+    )
+}
+
 #[test]
 fn dir_seed_10() -> TestResult {
     run(
@@ -92,6 +146,29 @@ fn dir_seed_10() -> TestResult {
     )
 }
 
+#[test]
+fn dir_seed_10_source_only() -> TestResult {
+    run(&[FORTUNE_DIR, "-s", "10", "--print-source-only"], "jokes\n")
+}
+
+#[test]
+fn source_index_0() -> TestResult {
+    run(
+        &[FORTUNE_DIR, "--source-index", "0", "--print-source-only"],
+        "ascii-art\n",
+    )
+}
+
+#[test]
+fn dies_source_index_out_of_range() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([JOKES, "--source-index", "1"])
+        .assert()
+        .failure()
+        .stderr("--source-index 1 out of range (1 sources)\n");
+    Ok(())
+}
+
 fn run_outfiles(args: &[&str], out_file: &str, err_file: &str) -> TestResult {
     let out = fs::read_to_string(out_file)?;
     let err = fs::read_to_string(err_file)?;
@@ -122,6 +199,22 @@ fn mark_twain_cap() -> TestResult {
     )
 }
 
+#[test]
+fn mark_twain_interleaved() -> TestResult {
+    // stdout と stderr を一つにまとめても、ヘッダーは各ソースの fortune の直前に出る
+    let out = fs::read_to_string("tests/expected/twain_cap.out")?;
+    let (literature, quotes) = out.split_at(out.find("Twenty").unwrap());
+    let expected = format!("(literature)\n%\n{}(quotes)\n%\n{}", literature, quotes);
+    Command::new("sh")
+        .args(["-c", "\"$0\" -m 'Mark Twain' \"$1\" 2>&1"])
+        .arg(assert_cmd::cargo::cargo_bin(PRG))
+        .arg(FORTUNE_DIR)
+        .assert()
+        .success()
+        .stdout(expected);
+    Ok(())
+}
+
 #[test]
 fn yogi_berra_lower() -> TestResult {
     run_outfiles(
@@ -157,3 +250,1003 @@ fn mark_twain_lower_i() -> TestResult {
         "tests/expected/twain_lower_i.err",
     )
 }
+
+#[test]
+fn find_duplicates() -> TestResult {
+    run(
+        &["--find-duplicates", DUPLICATES, JOKES],
+        "(duplicates, duplicates, jokes)\n\
+        Q: What happens when frogs park illegally?\nA: They get toad.\n%\n",
+    )
+}
+
+#[test]
+fn files_reports_empty_source() -> TestResult {
+    run(
+        &["-f", BLANK, JOKES, QUOTES],
+        concat!(
+            "  empty ./tests/fixtures/blank\n",
+            " 54.55% ./tests/inputs/jokes\n",
+            " 45.45% ./tests/inputs/quotes\n",
+        ),
+    )
+}
+
+#[test]
+fn all_sources_for_match() -> TestResult {
+    run(
+        &["--all-sources", "-m", "frogs", DUPLICATES, JOKES],
+        concat!(
+            "Q: What happens when frogs park illegally?\nA: They get toad.\n",
+            "(duplicates, jokes)\n%\n",
+            "Q: What happens when frogs park illegally?\nA: They get toad.\n",
+            "(duplicates, jokes)\n%\n",
+            "Q: What happens when frogs park illegally?\nA: They get toad.\n",
+            "(duplicates, jokes)\n%\n",
+        ),
+    )
+}
+
+#[test]
+fn merge_as_single_source() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--merge-as", "all", "-m", "Q:", DUPLICATES, JOKES])
+        .assert()
+        .success()
+        .stderr("(all)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn report_bytes() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args([JOKES, "-s", "1", "--line-ending", "crlf", "--report-bytes"])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        format!("{} bytes written\n", output.stdout.len())
+    );
+    Ok(())
+}
+
+#[test]
+fn count_out_weighted() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args([JOKES, QUOTES, "--count-out", "3", "-s", "1"])
+        .args(["--weight", "jokes=0", "--weight", "quotes=2"])
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert_eq!(stdout.matches("\n%\n").count(), 3);
+    assert!(!stdout.contains("Q:"));
+    Ok(())
+}
+
+#[test]
+fn invert_match() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-v", "-m", "Yogi Berra", QUOTES])
+        .assert()
+        .success()
+        .stderr("(quotes)\n%\n")
+        .stdout(predicate::str::contains("Mark Twain"))
+        .stdout(predicate::str::contains("Yogi Berra").not());
+    Ok(())
+}
+
+#[test]
+fn template_file() -> TestResult {
+    run(
+        &[
+            JOKES,
+            "-s",
+            "1",
+            "--template-file",
+            "tests/fixtures/template",
+        ],
+        concat!(
+            "== jokes (60 chars) ==\n",
+            "Q: What happens when frogs park illegally?\nA: They get toad.\n",
+        ),
+    )
+}
+
+#[test]
+fn no_match_exit_code() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-m", "Abraham Lincoln", "--no-match-exit-code", "3", QUOTES])
+        .assert()
+        .code(3)
+        .stdout("");
+
+    // 一致するものがあれば 0 で終了する
+    Command::cargo_bin(PRG)?
+        .args(["-m", "Yogi Berra", "--no-match-exit-code", "3", QUOTES])
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn match_filename() -> TestResult {
+    let expected = fs::read_to_string(JOKES)?;
+    Command::cargo_bin(PRG)?
+        .args(["--match-filename", "-m", "^jokes$", FORTUNE_DIR])
+        .assert()
+        .success()
+        .stderr("(jokes)\n%\n")
+        .stdout(expected.strip_suffix("%\n").unwrap().to_string());
+    Ok(())
+}
+
+#[test]
+fn by_id() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args([JOKES, "-s", "1", "--template", "{id}"])
+        .output()?;
+    let id = String::from_utf8(output.stdout)?;
+
+    run(
+        &[FORTUNE_DIR, "--by-id", id.trim()],
+        "Q: What happens when frogs park illegally?\nA: They get toad.\n",
+    )?;
+
+    Command::cargo_bin(PRG)?
+        .args([QUOTES, "--by-id", id.trim()])
+        .assert()
+        .failure()
+        .stderr(format!("No fortune with id {}", id));
+
+    // テキストを加工しても id は元の fortune のもの
+    let output = Command::cargo_bin(PRG)?
+        .args([JOKES, "-s", "1", "--first-sentence", "--template", "{id}"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, id);
+    Ok(())
+}
+
+#[test]
+fn stats_json() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--stats", "--json", JOKES, QUOTES])
+        .output()?;
+    assert!(output.status.success());
+
+    let stats: Value = serde_json::from_str(&String::from_utf8(output.stdout)?)?;
+    let field = |name| stats.get(name).and_then(|v| v.as_f64());
+    assert_eq!(field("files"), Some(2.0));
+    assert_eq!(field("fortunes"), Some(11.0));
+    assert_eq!(field("chars"), Some(1049.0));
+    assert_eq!(field("words"), Some(207.0));
+    assert_eq!(field("shortest"), Some(47.0));
+    assert_eq!(field("longest"), Some(249.0));
+    assert_eq!(field("average"), Some(1049.0 / 11.0));
+    Ok(())
+}
+
+#[test]
+fn summary() -> TestResult {
+    run(
+        &["--summary", FORTUNE_DIR],
+        "5 files, 19 fortunes, 4 sources matched\n",
+    )?;
+    run(
+        &["--summary", "-m", "Mark Twain", FORTUNE_DIR],
+        "5 files, 19 fortunes, 2 sources matched\n",
+    )
+}
+
+#[test]
+fn literal_pattern() -> TestResult {
+    run(
+        &["--literal", "-m", "a.b", "tests/fixtures/literal"],
+        "Use a.b for the attribute.\n%\n",
+    )?;
+    run(
+        &["--literal", "-m", "(", "tests/fixtures/literal"],
+        "Unbalanced ( parenthesis.\n%\n",
+    )
+}
+
+#[test]
+fn file_encoding() -> TestResult {
+    run(
+        &[
+            "--file-encoding",
+            "latin1=latin1",
+            "-m",
+            "café",
+            "tests/fixtures/latin1",
+            "tests/fixtures/utf8",
+        ],
+        "Un café crème, s'il vous plaît.\n%\nUn café crème, s'il vous plaît.\n%\n",
+    )?;
+    run(
+        &[
+            "--encoding",
+            "latin1",
+            "-m",
+            "café",
+            "tests/fixtures/latin1",
+        ],
+        "Un café crème, s'il vous plaît.\n%\n",
+    )
+}
+
+#[test]
+fn count_matching_sources() -> TestResult {
+    run(
+        &["--count-matching-sources", "-m", "Mark Twain", FORTUNE_DIR],
+        "2\n",
+    )?;
+    run(
+        &["--count-matching-sources", "-m", "Rust", FORTUNE_DIR],
+        "0\n",
+    )
+}
+
+#[test]
+fn under_prefix() -> TestResult {
+    run(
+        &["--under", "animals", "-m", ".", "tests/fixtures/nested"],
+        "A cat has nine lives.\n%\nDogs have owners, cats have staff.\n%\n",
+    )?;
+    run(
+        &[
+            "--under",
+            "tests/fixtures/nested/plants",
+            "-m",
+            ".",
+            "tests/fixtures/nested",
+        ],
+        "The best time to plant a tree was 20 years ago.\n%\n",
+    )
+}
+
+#[test]
+fn max_width() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--max-width", "40", "tests/fixtures/wide"])
+        .assert()
+        .failure()
+        .stdout("wide: fortune 2 has a line wider than 40\n")
+        .stderr("1 fortunes have lines wider than 40\n");
+
+    run(&["--max-width", "100", "tests/fixtures/wide"], "")
+}
+
+#[test]
+fn rename_sources() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([
+            "--rename",
+            "s/_/ /g",
+            "-m",
+            "noodle",
+            "tests/fixtures/dad_jokes",
+        ])
+        .assert()
+        .success()
+        .stdout("What do you call a fake noodle? An impasta.\n%\n")
+        .stderr("(dad jokes)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn stats_bytes_per_source() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--stats", "--json", JOKES, QUOTES, "tests/fixtures/utf8"])
+        .output()?;
+    assert!(output.status.success());
+
+    let stats: Value = serde_json::from_str(&String::from_utf8(output.stdout)?)?;
+    let bytes = |source| {
+        stats
+            .get("bytes")
+            .and_then(|b| b.get(source))
+            .and_then(|v| v.as_f64())
+    };
+    assert_eq!(bytes("jokes"), Some(471.0));
+    assert_eq!(bytes("quotes"), Some(578.0));
+    // 文字数ではなくバイト数を数える
+    assert_eq!(bytes("utf8"), Some(34.0));
+    Ok(())
+}
+
+#[test]
+fn blank_separator() -> TestResult {
+    run(
+        &["--blank-separator", "-m", "Yogi Berra", QUOTES],
+        "It's like deja vu all over again.\n-- Yogi Berra\n\n\
+         You can observe a lot just by watching.\n-- Yogi Berra\n",
+    )?;
+    run(
+        &[
+            "--blank-separator",
+            "--count-out",
+            "2",
+            "-s",
+            "1",
+            "tests/fixtures/nested",
+        ],
+        "The best time to plant a tree was 20 years ago.\n\nDogs have owners, cats have staff.\n",
+    )
+}
+
+#[test]
+fn detect_eol() -> TestResult {
+    run(
+        &["--detect-eol", "tests/fixtures/crlf", JOKES],
+        "   LF ./tests/inputs/jokes\n CRLF tests/fixtures/crlf\n",
+    )?;
+
+    // 標準入力も読める
+    Command::cargo_bin(PRG)?
+        .args(["--detect-eol", "-"])
+        .write_stdin("a\r\n%\r\nbb\r\n")
+        .assert()
+        .success()
+        .stdout(" CRLF -\n");
+    Ok(())
+}
+
+#[test]
+fn max_total() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--max-total", "2", "-m", ".", JOKES, QUOTES])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.matches("\n%\n").count() == 2
+        }))
+        .stderr("Warning: stopped reading after 2 fortunes\n(jokes)\n%\n");
+
+    // ちょうど N 件なら何も捨てていないので警告しない
+    Command::cargo_bin(PRG)?
+        .args(["--max-total", "6", "-m", ".", JOKES])
+        .assert()
+        .success()
+        .stderr("(jokes)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn tag_from_dir() -> TestResult {
+    run(
+        &["--tag-from-dir", "--tag", "plants", "tests/fixtures/nested"],
+        "The best time to plant a tree was 20 years ago.\n",
+    )?;
+    run(
+        &[
+            "--tag-from-dir",
+            "--tag",
+            "animals",
+            "-m",
+            "a",
+            "tests/fixtures/nested",
+        ],
+        "A cat has nine lives.\n%\nDogs have owners, cats have staff.\n%\n",
+    )?;
+
+    // タグはテンプレートと JSON からも参照できる
+    run(
+        &[
+            "--tag-from-dir",
+            "--template",
+            "[{tag}] {text}",
+            "-m",
+            "tree",
+            "tests/fixtures/nested",
+        ],
+        "[plants] The best time to plant a tree was 20 years ago.\n%\n",
+    )?;
+    let output = Command::cargo_bin(PRG)?
+        .args([
+            "--tag-from-dir",
+            "--json",
+            "-m",
+            "tree",
+            "tests/fixtures/nested",
+        ])
+        .output()?;
+    let matches: Value = serde_json::from_str(&String::from_utf8(output.stdout)?)?;
+    assert_eq!(
+        matches[0].get("tag").and_then(|v| v.as_str()),
+        Some("plants")
+    );
+    Ok(())
+}
+
+#[test]
+fn silent_fail() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--silent-fail", "-m", "Abraham Lincoln", QUOTES])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("");
+
+    Command::cargo_bin(PRG)?
+        .args(["--silent-fail", "--json", "-m", "Abraham Lincoln", QUOTES])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("");
+
+    Command::cargo_bin(PRG)?
+        .args(["--silent-fail", "-m", "Yogi Berra", QUOTES])
+        .assert()
+        .success();
+
+    Command::cargo_bin(PRG)?
+        .args(["--silent-fail", "--fallback", "none", "-m", "Yogi", QUOTES])
+        .assert()
+        .failure()
+        .stderr("--silent-fail cannot be used with --fallback or --no-match-exit-code\n");
+    Ok(())
+}
+
+#[test]
+fn auto_strip_prefix() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--auto-strip-prefix", "-m", "e", "tests/fixtures/nested"])
+        .assert()
+        .success()
+        .stderr("(animals/pets)\n%\n(plants/trees)\n%\n");
+
+    Command::cargo_bin(PRG)?
+        .args([
+            "--auto-strip-prefix",
+            "--print-source-only",
+            "--seed",
+            "1",
+            JOKES,
+        ])
+        .assert()
+        .success()
+        .stdout("jokes\n");
+    Ok(())
+}
+
+#[test]
+fn unique_priority() -> TestResult {
+    let frogs = "Q: What happens when frogs park illegally?\nA: They get toad.\n%\n";
+    Command::cargo_bin(PRG)?
+        .args(["--unique", "-m", "frogs", JOKES, DUPLICATES])
+        .assert()
+        .success()
+        .stdout(frogs)
+        .stderr("(duplicates)\n%\n");
+
+    Command::cargo_bin(PRG)?
+        .args([
+            "--unique",
+            "--priority",
+            "jokes",
+            "-m",
+            "frogs",
+            JOKES,
+            DUPLICATES,
+        ])
+        .assert()
+        .success()
+        .stdout(frogs)
+        .stderr("(jokes)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn parse_warnings() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--warnings", "-m", "fortune", "tests/fixtures/fixups"])
+        .assert()
+        .success()
+        .stderr(
+            "5 parse warnings (2 empty records skipped, 1 unterminated records flushed, \
+             2 CRLF line endings fixed)\n(fixups)\n%\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn dies_warnings_with_cache() -> TestResult {
+    // キャッシュしたインデックスからは警告を数えられない
+    let cache_dir = std::env::temp_dir().join(format!("fortuner-cli-cache-{}", std::process::id()));
+    Command::cargo_bin(PRG)?
+        .args(["--warnings", "-m", "fortune", "tests/fixtures/fixups"])
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .assert()
+        .failure()
+        .stderr("--warnings cannot be used with --cache-dir\n");
+    assert!(fs::metadata(&cache_dir).is_err());
+    Ok(())
+}
+
+#[test]
+fn match_timeout() -> TestResult {
+    // 大量の fortune を照合し終える前に時間切れになる
+    let input = "A short fortune to match against.\n%\n".repeat(200_000);
+    for json in [&[][..], &["--json"]] {
+        Command::cargo_bin(PRG)?
+            .args(["--match-timeout", "1", "-m", "(a|b)+z", "-"])
+            .args(json)
+            .write_stdin(input.as_str())
+            .assert()
+            .failure()
+            .stdout("")
+            .stderr("Pattern matching exceeded 1 ms\n");
+    }
+
+    Command::cargo_bin(PRG)?
+        .args(["--match-timeout", "60000", "-m", "Yogi", FORTUNE_DIR])
+        .assert()
+        .success();
+
+    Command::cargo_bin(PRG)?
+        .args(["--match-timeout", "0", "-m", "Yogi", FORTUNE_DIR])
+        .assert()
+        .failure()
+        .stderr("--match-timeout must be greater than 0\n");
+    Ok(())
+}
+
+#[test]
+fn stdin_name() -> TestResult {
+    let input = "Read from a pipe.\n%\n";
+    Command::cargo_bin(PRG)?
+        .args(["-m", "pipe", "-"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(input)
+        .stderr("(stdin)\n%\n");
+
+    Command::cargo_bin(PRG)?
+        .args(["--stdin-name", "piped", "-m", "pipe", "-", JOKES])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(input)
+        .stderr("(piped)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn dump_offsets() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--dump-offsets", JOKES, "tests/fixtures/crlf"])
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut records = 0;
+    for line in stdout.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [path, offset, len] = fields[..] else {
+            panic!("unexpected line {:?}", line);
+        };
+        let (offset, len): (usize, usize) = (offset.parse()?, len.parse()?);
+
+        // オフセットはレコードの先頭を指し、長さ分で本文全体になる
+        let bytes = fs::read(path)?;
+        assert!(offset == 0 || bytes[offset - 1] == b'\n');
+        let text = std::str::from_utf8(&bytes[offset..offset + len])?;
+        assert!(!text.starts_with('%') && !text.ends_with('\n'));
+        records += 1;
+    }
+    assert_eq!(records, 7);
+    assert!(stdout.starts_with("./tests/inputs/jokes\t0\t77\n./tests/inputs/jokes\t80\t71\n"));
+    assert!(stdout.ends_with("tests/fixtures/crlf\t0\t27\n"));
+
+    Command::cargo_bin(PRG)?
+        .args(["--dump-offsets", "-"])
+        .write_stdin("a\r\n%\r\nbb\r\n")
+        .assert()
+        .success()
+        .stdout("-\t0\t1\n-\t6\t2\n");
+
+    let jokes = fs::read_to_string(JOKES)?;
+    assert_eq!(
+        &jokes[80..80 + 71],
+        "Q: Why did the gardener quit his job?\nA: His celery wasn't high enough."
+    );
+    Ok(())
+}
+
+#[test]
+fn warn_source_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--stats", "--warn-source-count", "3", FORTUNE_DIR])
+        .assert()
+        .success()
+        .stderr("Warning: 5 sources exceed --warn-source-count 3\n");
+
+    Command::cargo_bin(PRG)?
+        .args(["--stats", "--warn-source-count", "5", FORTUNE_DIR])
+        .assert()
+        .success()
+        .stderr("");
+    Ok(())
+}
+
+#[test]
+fn log_and_replay() -> TestResult {
+    let log = std::env::temp_dir().join(format!("fortuner-log-{}.tsv", std::process::id()));
+    let _ = fs::remove_file(&log);
+    let log = log.to_str().unwrap();
+
+    Command::cargo_bin(PRG)?
+        .args(["--log", log, "--seed", "1", QUOTES])
+        .assert()
+        .success();
+    let picked = Command::cargo_bin(PRG)?
+        .args(["--log", log, "--seed", "3", JOKES])
+        .output()?;
+
+    // 最後に記録した fortune が再現される
+    let replayed = Command::cargo_bin(PRG)?
+        .args(["--replay", log, FORTUNE_DIR])
+        .output()?;
+    let logged = fs::read_to_string(log)?;
+    fs::remove_file(log)?;
+
+    assert!(replayed.status.success());
+    assert_eq!(replayed.stdout, picked.stdout);
+    let lines: Vec<&str> = logged.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("\tquotes\t1"));
+    assert!(lines[1].ends_with("\tjokes\t3"));
+    Ok(())
+}
+
+#[test]
+fn os_random_print_seed() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--os-random", "--print-seed", FORTUNE_DIR])
+        .output()?;
+    assert!(output.status.success());
+
+    // 表示されたシードを --seed に渡すと同じ fortune になる
+    let stderr = String::from_utf8(output.stderr)?;
+    let seed = stderr
+        .strip_prefix("Seed: ")
+        .and_then(|s| s.strip_suffix('\n'))
+        .ok_or("no seed printed")?;
+    let replayed = Command::cargo_bin(PRG)?
+        .args(["--seed", seed, FORTUNE_DIR])
+        .output()?;
+    assert_eq!(replayed.stdout, output.stdout);
+    Ok(())
+}
+
+#[test]
+fn json_matches_with_details() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--json", "--json-details", "-m", "Yogi Berra", QUOTES])
+        .output()?;
+    assert!(output.status.success());
+
+    let matches: Value = serde_json::from_str(&String::from_utf8(output.stdout)?)?;
+    let matches = matches.as_array().ok_or("expected an array")?;
+    assert_eq!(matches.len(), 2);
+
+    let first = &matches[0];
+    let text = "It's like deja vu all over again.\n-- Yogi Berra";
+    assert_eq!(first.get("source").and_then(|v| v.as_str()), Some("quotes"));
+    assert_eq!(first.get("text").and_then(|v| v.as_str()), Some(text));
+    assert_eq!(first.get("length").and_then(|v| v.as_f64()), Some(47.0));
+
+    // id は --by-id でその fortune を引ける
+    let id = first.get("id").and_then(|v| v.as_str()).ok_or("no id")?;
+    run(
+        &["--by-id", id, QUOTES],
+        "It's like deja vu all over again.\n-- Yogi Berra\n",
+    )?;
+
+    let output = Command::cargo_bin(PRG)?
+        .args(["--json", "-m", "Yogi Berra", QUOTES])
+        .output()?;
+    let matches: Value = serde_json::from_str(&String::from_utf8(output.stdout)?)?;
+    assert!(matches.as_array().unwrap()[0].get("id").is_none());
+    Ok(())
+}
+
+#[test]
+fn flatten_directory() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--flatten", "-m", "e", "tests/fixtures/nested", JOKES])
+        .assert()
+        .success()
+        .stderr("(jokes)\n%\n(nested)\n%\n");
+    Ok(())
+}
+
+#[test]
+fn files_absolute() -> TestResult {
+    let expected = format!(
+        "  empty {}\n 54.55% {}\n 45.45% {}\n",
+        fs::canonicalize(BLANK)?.display(),
+        fs::canonicalize(JOKES)?.display(),
+        fs::canonicalize(QUOTES)?.display()
+    );
+    Command::cargo_bin(PRG)?
+        .args(["-f", "--absolute", BLANK, JOKES, QUOTES])
+        .assert()
+        .success()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn round_robin_state() -> TestResult {
+    let state = std::env::temp_dir().join(format!("fortuner-rr-{}", std::process::id()));
+    let _ = fs::remove_file(&state);
+    let state = state.to_str().unwrap();
+
+    // 実行するたびに次の fortune へ進み、最後の次は先頭に戻る
+    let mut outputs = vec![];
+    for _ in 0..4 {
+        let output = Command::cargo_bin(PRG)?
+            .args(["--round-robin-state", state, "tests/fixtures/nested"])
+            .output()?;
+        assert!(output.status.success());
+        outputs.push(String::from_utf8(output.stdout)?);
+    }
+    fs::remove_file(state)?;
+
+    assert_eq!(
+        outputs,
+        [
+            "A cat has nine lives.\n",
+            "Dogs have owners, cats have staff.\n",
+            "The best time to plant a tree was 20 years ago.\n",
+            "A cat has nine lives.\n",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn min_alpha_ratio() -> TestResult {
+    run(
+        &["--min-alpha-ratio", "0.5", "--summary", FORTUNE_DIR],
+        "5 files, 15 fortunes, 3 sources matched\n",
+    )?;
+    Command::cargo_bin(PRG)?
+        .args(["--min-alpha-ratio", "1.5", JOKES])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn pretty_json() -> TestResult {
+    let output = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin(PRG)?.args(args).output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    let compact = output(&["--json", "-m", "Yogi Berra", QUOTES])?;
+    let pretty = output(&["--json", "--pretty", "-m", "Yogi Berra", QUOTES])?;
+    assert!(pretty.starts_with("[\n  {\n    \"source\": \"quotes\",\n"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&pretty)?,
+        serde_json::from_str::<Value>(&compact)?
+    );
+
+    // パターンなしで選んだ fortune も JSON で出力する
+    let picked: Value = serde_json::from_str(&output(&["--json", "--pretty", "-s", "1", JOKES])?)?;
+    assert_eq!(picked.get("source").and_then(|v| v.as_str()), Some("jokes"));
+    let picked: Value = serde_json::from_str(&output(&["--json", "--count-out", "2", JOKES])?)?;
+    assert_eq!(picked.as_array().map(Vec::len), Some(2));
+    Ok(())
+}
+
+#[test]
+fn exclude_source_index() -> TestResult {
+    run(
+        &["--exclude-source-index", "0", "-m", "Yogi", JOKES, QUOTES],
+        "It's like deja vu all over again.\n-- Yogi Berra\n%\n\
+         You can observe a lot just by watching.\n-- Yogi Berra\n%\n",
+    )?;
+    run(
+        &["--exclude-source-index", "1", "-m", "Yogi", JOKES, QUOTES],
+        "",
+    )?;
+
+    Command::cargo_bin(PRG)?
+        .args([JOKES, "--exclude-source-index", "1"])
+        .assert()
+        .failure()
+        .stderr("--exclude-source-index 1 out of range (1 sources)\n");
+    Ok(())
+}
+
+#[test]
+fn count_range() -> TestResult {
+    run(&["--count-range", "70-80", JOKES], "3\n")?;
+    run(&["--count-range", "0-1000", JOKES, QUOTES], "11\n")
+}
+
+#[test]
+fn show_pattern() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--show-pattern", "-m", "Yogi", "-m", "deja", QUOTES])
+        .assert()
+        .success()
+        .stdout(
+            "It's like deja vu all over again.\n-- Yogi Berra\n%\n\
+             You can observe a lot just by watching.\n-- Yogi Berra\n%\n",
+        )
+        .stderr("(quotes)\n%\n[Yogi, deja]\n[Yogi]\n");
+    Ok(())
+}
+
+#[test]
+fn shown_file() -> TestResult {
+    let shown = std::env::temp_dir().join(format!("fortuner-shown-{}", std::process::id()));
+    let _ = fs::remove_file(&shown);
+    let shown = shown.to_str().unwrap();
+
+    let mut outputs = vec![];
+    for _ in 0..6 {
+        let output = Command::cargo_bin(PRG)?
+            .args(["--shown-file", shown, "tests/fixtures/nested"])
+            .output()?;
+        assert!(output.status.success());
+        outputs.push(String::from_utf8(output.stdout)?);
+    }
+    fs::remove_file(shown)?;
+
+    // 3 つすべてを表示するまで同じ fortune は繰り返されない
+    for round in outputs.chunks(3) {
+        let mut round = round.to_vec();
+        round.sort();
+        assert_eq!(
+            round,
+            [
+                "A cat has nine lives.\n",
+                "Dogs have owners, cats have staff.\n",
+                "The best time to plant a tree was 20 years ago.\n",
+            ]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn no_trailing_separator() -> TestResult {
+    run(
+        &["--no-trailing-separator", "-m", "Yogi", QUOTES],
+        "It's like deja vu all over again.\n-- Yogi Berra\n%\n\
+         You can observe a lot just by watching.\n-- Yogi Berra\n",
+    )?;
+    run(
+        &[
+            "--no-trailing-separator",
+            "--count-out",
+            "1",
+            "-s",
+            "1",
+            QUOTES,
+        ],
+        "You can observe a lot just by watching.\n-- Yogi Berra\n",
+    )
+}
+
+#[test]
+fn max_line_bytes() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([
+            "--max-line-bytes",
+            "100",
+            "-m",
+            "[a-z]",
+            "tests/fixtures/long-line",
+            "tests/fixtures/tiny",
+        ])
+        .assert()
+        .success()
+        .stdout("Hi.\n%\n")
+        .stderr("Warning: long-line: line longer than 100 bytes, skipping\n(tiny)\n%\n");
+
+    run(
+        &[
+            "--max-line-bytes",
+            "300",
+            "-m",
+            "Short",
+            "tests/fixtures/long-line",
+        ],
+        "Short and sweet.\n%\n",
+    )
+}
+
+#[test]
+fn alias_file() -> TestResult {
+    run(
+        &[
+            "--alias-file",
+            "tests/fixtures/aliases",
+            "-m",
+            "Yogi",
+            "wisdom",
+        ],
+        "It's like deja vu all over again.\n-- Yogi Berra\n%\n\
+         You can observe a lot just by watching.\n-- Yogi Berra\n%\n",
+    )?;
+
+    // エイリアスは参照先のファイルと同じ結果になる
+    let aliased = Command::cargo_bin(PRG)?
+        .args(["--alias-file", "tests/fixtures/aliases", "-s", "1", "jokes"])
+        .output()?;
+    let direct = Command::cargo_bin(PRG)?.args(["-s", "1", JOKES]).output()?;
+    assert!(aliased.status.success());
+    assert_eq!(aliased.stdout, direct.stdout);
+
+    // エイリアスでない名前はそのままパスとして扱われる
+    Command::cargo_bin(PRG)?
+        .args(["--alias-file", "tests/fixtures/aliases", "nope"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("nope"));
+    Ok(())
+}
+
+#[test]
+fn show_probability() -> TestResult {
+    // jokes 6 件 (重み 3) と quotes 5 件 (重み 1) から選ぶ
+    let weighted = [
+        "--weight",
+        "jokes=3",
+        "--show-probability",
+        "--print-source-only",
+    ];
+    Command::cargo_bin(PRG)?
+        .args(weighted)
+        .args(["-s", "1", JOKES, QUOTES])
+        .assert()
+        .success()
+        .stdout("quotes\n")
+        .stderr("Probability: 0.0435\n");
+    Command::cargo_bin(PRG)?
+        .args(weighted)
+        .args(["-s", "2", JOKES, QUOTES])
+        .assert()
+        .success()
+        .stdout("jokes\n")
+        .stderr("Probability: 0.1304\n");
+
+    Command::cargo_bin(PRG)?
+        .args(["--show-probability", "-s", "1", JOKES])
+        .assert()
+        .success()
+        .stderr("Probability: 0.1667\n");
+    Ok(())
+}
+
+#[test]
+fn files_counts_single_read() -> TestResult {
+    // stdin も一度だけ読んで数える
+    Command::cargo_bin(PRG)?
+        .args(["-f", "-"])
+        .write_stdin("a\n%\n")
+        .assert()
+        .success()
+        .stdout("100.00% -\n");
+
+    // 絞り込みで fortune がなくなっても割合は読み込んだ数に対して出す
+    run(
+        &["-f", "--min-line-length", "999", JOKES, QUOTES],
+        concat!(
+            " 54.55% ./tests/inputs/jokes\n",
+            " 45.45% ./tests/inputs/quotes\n"
+        ),
+    )?;
+    run(
+        &["-f", "--encoding", "latin1", "tests/fixtures/latin1"],
+        "100.00% tests/fixtures/latin1\n",
+    )
+}